@@ -0,0 +1,355 @@
+//! Batched datagram sends
+//!
+//! Sending each datagram with its own `send_to` costs a syscall per packet,
+//! which caps an emitter well below what loopback or a NIC will carry. A
+//! `Batch` gathers many pre-built datagrams and, on Linux, hands them to the
+//! kernel in a single `sendmmsg`. With segmentation offload enabled the batch
+//! instead goes out as one `sendmsg` carrying a `UDP_SEGMENT` control
+//! message, the kernel -- or the NIC -- splitting it into datagrams.
+//!
+//! Segmentation offload requires every datagram but the last to be the same
+//! size, so in that mode shorter datagrams are padded with trailing newlines.
+//! Statsd servers read those as empty lines.
+//!
+//! Kernels without `UDP_SEGMENT` refuse such a send, in which case the batch
+//! falls back to `sendmmsg` for good.
+//!
+//! Elsewhere a `Batch` falls back to one `send_to` per datagram.
+
+#[cfg(target_os = "linux")]
+use libc;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::thread;
+
+/// The most segments the kernel accepts in one UDP_SEGMENT send
+pub const MAX_SEGMENTS: usize = 64;
+/// The largest UDP payload, bounding a segmented send
+const MAX_UDP_PAYLOAD: usize = 65_507;
+
+/// Outcome of sending a batch
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Sent {
+    /// Datagrams sent
+    pub datagrams: usize,
+    /// Lines carried by those datagrams
+    pub lines: usize,
+    /// Syscalls made to send them
+    pub syscalls: usize,
+}
+
+/// A collection of datagrams waiting to be sent together
+pub struct Batch {
+    datagrams: Vec<Vec<u8>>,
+    lines: Vec<usize>,
+    len: usize,
+    segment: bool,
+    scratch: Vec<u8>,
+}
+
+impl Batch {
+    /// Create a new Batch holding up to `capacity` datagrams
+    ///
+    /// If `segment` is set the batch is sent with UDP segmentation offload
+    /// and is capped at `MAX_SEGMENTS` datagrams.
+    pub fn new(capacity: usize, segment: bool) -> Batch {
+        let capacity = if segment {
+            capacity.min(MAX_SEGMENTS)
+        } else {
+            capacity
+        };
+        Batch {
+            datagrams: (0..capacity.max(1)).map(|_| Vec::new()).collect(),
+            lines: vec![0; capacity.max(1)],
+            len: 0,
+            segment,
+            scratch: Vec::new(),
+        }
+    }
+
+    /// Add a datagram of `lines` lines to the batch
+    ///
+    /// The caller must check `is_full` before pushing.
+    pub fn push(&mut self, datagram: &[u8], lines: usize) {
+        let slot = &mut self.datagrams[self.len];
+        slot.clear();
+        slot.extend_from_slice(datagram);
+        self.lines[self.len] = lines;
+        self.len += 1;
+    }
+
+    /// Whether the batch can take no more datagrams
+    pub fn is_full(&self) -> bool {
+        self.len == self.datagrams.len()
+    }
+
+    /// Whether the batch holds no datagrams
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether the batch is sent with segmentation offload
+    pub fn is_segmented(&self) -> bool {
+        self.segment
+    }
+
+    /// Send every datagram in the batch to `dest`, emptying it
+    ///
+    /// A socket that would block is retried until the whole batch is out. A
+    /// segmented send the kernel refuses is retried with `sendmmsg`, which
+    /// every later send then uses.
+    pub fn send(&mut self, socket: &UdpSocket, dest: &SocketAddr) -> io::Result<Sent> {
+        let mut sent = Sent::default();
+        let mut start = 0;
+        while start < self.len {
+            let res = if self.segment {
+                self.send_segmented(socket, dest, start)
+            } else {
+                self.send_many(socket, dest, start)
+            };
+            sent.syscalls += 1;
+            match res {
+                Ok(n) => {
+                    sent.datagrams += n;
+                    sent.lines += self.lines[start..start + n].iter().sum::<usize>();
+                    start += n;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::yield_now(),
+                Err(ref e) if self.segment && unsupported(e) => self.segment = false,
+                Err(e) => {
+                    self.len = 0;
+                    return Err(e);
+                }
+            }
+        }
+        self.len = 0;
+        Ok(sent)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn send_many(&mut self, socket: &UdpSocket, dest: &SocketAddr, start: usize) -> io::Result<usize> {
+        socket.send_to(&self.datagrams[start], dest).map(|_| 1)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn send_segmented(
+        &mut self,
+        socket: &UdpSocket,
+        dest: &SocketAddr,
+        start: usize,
+    ) -> io::Result<usize> {
+        self.send_many(socket, dest, start)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn send_many(&mut self, socket: &UdpSocket, dest: &SocketAddr, start: usize) -> io::Result<usize> {
+        sys::sendmmsg(socket, dest, &self.datagrams[start..self.len])
+    }
+
+    #[cfg(target_os = "linux")]
+    fn send_segmented(
+        &mut self,
+        socket: &UdpSocket,
+        dest: &SocketAddr,
+        start: usize,
+    ) -> io::Result<usize> {
+        let pending = &self.datagrams[start..self.len];
+        let segment = pending.iter().map(|d| d.len()).max().unwrap_or(0).max(1);
+        let count = pending.len().min((MAX_UDP_PAYLOAD / segment).max(1));
+        self.scratch.clear();
+        for (i, datagram) in pending[..count].iter().enumerate() {
+            self.scratch.extend_from_slice(datagram);
+            if i + 1 < count {
+                let pad = segment - datagram.len();
+                self.scratch.extend((0..pad).map(|_| b'\n'));
+            }
+        }
+        sys::send_segmented(socket, dest, &self.scratch, segment as u16).map(|_| count)
+    }
+}
+
+/// Whether `e` is the kernel refusing a `UDP_SEGMENT` send
+#[cfg(target_os = "linux")]
+fn unsupported(e: &io::Error) -> bool {
+    match e.raw_os_error() {
+        Some(code) => code == libc::EINVAL || code == libc::EIO,
+        None => false,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported(_e: &io::Error) -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use libc;
+    use std::io;
+    use std::mem;
+    use std::net::{SocketAddr, UdpSocket};
+    use std::os::unix::io::AsRawFd;
+    use std::ptr;
+
+    const SOL_UDP: libc::c_int = 17;
+    const UDP_SEGMENT: libc::c_int = 103;
+
+    fn sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        unsafe {
+            let mut storage: libc::sockaddr_storage = mem::zeroed();
+            let len = match *addr {
+                SocketAddr::V4(ref a) => {
+                    let sin = &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in);
+                    sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                    sin.sin_port = a.port().to_be();
+                    sin.sin_addr.s_addr = u32::from(*a.ip()).to_be();
+                    mem::size_of::<libc::sockaddr_in>()
+                }
+                SocketAddr::V6(ref a) => {
+                    let sin6 = &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6);
+                    sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                    sin6.sin6_port = a.port().to_be();
+                    sin6.sin6_flowinfo = a.flowinfo();
+                    sin6.sin6_addr.s6_addr = a.ip().octets();
+                    sin6.sin6_scope_id = a.scope_id();
+                    mem::size_of::<libc::sockaddr_in6>()
+                }
+            };
+            (storage, len as libc::socklen_t)
+        }
+    }
+
+    pub fn sendmmsg(socket: &UdpSocket, dest: &SocketAddr, datagrams: &[Vec<u8>]) -> io::Result<usize> {
+        let (mut addr, addr_len) = sockaddr(dest);
+        let mut iovecs: Vec<libc::iovec> = datagrams
+            .iter()
+            .map(|d| libc::iovec {
+                iov_base: d.as_ptr() as *mut libc::c_void,
+                iov_len: d.len(),
+            })
+            .collect();
+        let mut msgs: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .map(|iov| {
+                let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+                msg.msg_hdr.msg_name = &mut addr as *mut _ as *mut libc::c_void;
+                msg.msg_hdr.msg_namelen = addr_len;
+                msg.msg_hdr.msg_iov = iov as *mut libc::iovec;
+                msg.msg_hdr.msg_iovlen = 1;
+                msg
+            })
+            .collect();
+        let res = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                msgs.len() as libc::c_uint,
+                0,
+            )
+        };
+        if res < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(res as usize)
+        }
+    }
+
+    pub fn send_segmented(
+        socket: &UdpSocket,
+        dest: &SocketAddr,
+        buf: &[u8],
+        segment: u16,
+    ) -> io::Result<usize> {
+        let (mut addr, addr_len) = sockaddr(dest);
+        let mut iov = libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        // a cmsghdr followed by a u16, each padded to the kernel's alignment
+        let align = mem::size_of::<usize>();
+        let hdr_len = (mem::size_of::<libc::cmsghdr>() + align - 1) & !(align - 1);
+        let space = hdr_len + ((mem::size_of::<u16>() + align - 1) & !(align - 1));
+        let mut control = vec![0u8; space];
+        unsafe {
+            let cmsg = control.as_mut_ptr() as *mut libc::cmsghdr;
+            (*cmsg).cmsg_level = SOL_UDP;
+            (*cmsg).cmsg_type = UDP_SEGMENT;
+            (*cmsg).cmsg_len = (hdr_len + mem::size_of::<u16>()) as _;
+            ptr::write_unaligned(control.as_mut_ptr().add(hdr_len) as *mut u16, segment);
+
+            let mut msg: libc::msghdr = mem::zeroed();
+            msg.msg_name = &mut addr as *mut _ as *mut libc::c_void;
+            msg.msg_namelen = addr_len;
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = space as _;
+
+            let res = libc::sendmsg(socket.as_raw_fd(), &msg, 0);
+            if res < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(res as usize)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn receive(socket: &UdpSocket, count: usize) -> Vec<Vec<u8>> {
+        let mut buf = vec![0; 65_536];
+        (0..count)
+            .map(|_| {
+                let read = socket.recv(&mut buf).unwrap();
+                buf[..read].to_vec()
+            })
+            .collect()
+    }
+
+    fn roundtrip(segment: bool) {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        listener
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let dest = listener.local_addr().unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let datagrams: Vec<&[u8]> = vec![b"a:1|c\nb:2|c", b"c:3|g", b"d:4|ms\ne:5|h"];
+
+        let mut batch = Batch::new(8, segment);
+        for datagram in &datagrams {
+            batch.push(datagram, 2);
+        }
+        let sent = batch.send(&socket, &dest).unwrap();
+        assert_eq!(sent.datagrams, 3);
+        assert_eq!(sent.lines, 6);
+        assert!(batch.is_empty());
+
+        // segmented datagrams may be padded with newlines
+        for (received, datagram) in receive(&listener, 3).iter().zip(&datagrams) {
+            let end = received.iter().rposition(|b| *b != b'\n').unwrap() + 1;
+            assert_eq!(&received[..end], *datagram);
+        }
+    }
+
+    #[test]
+    fn batches_arrive_whole() {
+        roundtrip(false);
+    }
+
+    #[test]
+    fn segmented_batches_arrive_whole_with_or_without_offload() {
+        roundtrip(true);
+    }
+
+    #[test]
+    fn segmented_batches_are_capped() {
+        let batch = Batch::new(1000, true);
+        assert!(batch.is_segmented());
+        assert_eq!(batch.datagrams.len(), MAX_SEGMENTS);
+        assert_eq!(Batch::new(1000, false).datagrams.len(), 1000);
+    }
+}
@@ -1,14 +1,27 @@
 extern crate clap;
 #[macro_use]
 extern crate lazy_static;
+extern crate llrv;
 extern crate rand;
 
-use clap::{App, Arg};
-use rand::{thread_rng, Rng};
-use std::net::UdpSocket;
-use std::net::Ipv4Addr;
-use std::net::SocketAddrV4;
-use std::sync::Arc;
+use clap::{App, Arg, ArgMatches};
+use llrv::batch::Batch;
+use llrv::generator;
+use llrv::generator::churn::Churn;
+use llrv::generator::dogstatsd::DogStatsd;
+use llrv::generator::names::{NameConfig, Template};
+use llrv::generator::statsd::{MetricType, Mix, SampleRates, StatsdConfig, StatsdGenerator,
+                              Tally, Values};
+use llrv::generator::selection::Selection;
+use llrv::pacer::Pacer;
+use llrv::profile::{parse_positive_rate, Profile};
+use llrv::scenario::Scenario;
+use llrv::search::{self, Trials};
+use llrv::sequence;
+use llrv::transport::{connect, resolve, Datagram, Endpoint, Stream};
+use rand::{Rng, XorShiftRng};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time;
 use std::thread;
@@ -16,16 +29,50 @@ use std::thread;
 lazy_static! {
     static ref LINES_WRITTEN: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
     static ref PACKETS_WRITTEN: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+    static ref MAX_LAG_US: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+    static ref TARGET_RATE: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+    static ref SYSCALLS: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+    // each worker's tally and churn retirements, published once a second
+    static ref TALLIES: Mutex<Vec<(Tally, usize)>> = Mutex::new(Vec::new());
 }
 
-fn tick() {
+fn tick(paced: bool, churn: bool) {
     loop {
         let packets = PACKETS_WRITTEN.swap(0, Ordering::Relaxed);
         let lines = LINES_WRITTEN.swap(0, Ordering::Relaxed);
-        println!(
-            "LINES PER SECOND: {} | TOTAL PACKETS PER SECOND: {}",
-            lines, packets
-        );
+        let syscalls = SYSCALLS.swap(0, Ordering::Relaxed);
+        if paced {
+            let lag = MAX_LAG_US.swap(0, Ordering::Relaxed);
+            let target = TARGET_RATE.load(Ordering::Relaxed);
+            println!(
+                "LINES PER SECOND: {} | TOTAL PACKETS PER SECOND: {} | SYSCALLS PER SECOND: {} | TARGET RATE: {} | MAX BEHIND SCHEDULE: {}us",
+                lines, packets, syscalls, target, lag
+            );
+        } else {
+            println!(
+                "LINES PER SECOND: {} | TOTAL PACKETS PER SECOND: {} | SYSCALLS PER SECOND: {}",
+                lines, packets, syscalls
+            );
+        }
+        let mut tally = Tally::default();
+        let mut retired = 0;
+        for &(ref worker_tally, worker_retired) in TALLIES.lock().unwrap().iter() {
+            tally.merge(worker_tally);
+            retired += worker_retired;
+        }
+        let types: Vec<String> = MetricType::ALL
+            .iter()
+            .map(|t| format!("{}: {:.1}", t, tally.effective(*t)))
+            .collect();
+        if churn {
+            println!(
+                "  TOTAL EFFECTIVE COUNTS: {} | TOTAL RETIRED BY CHURN: {}",
+                types.join(" | "),
+                retired
+            );
+        } else {
+            println!("  TOTAL EFFECTIVE COUNTS: {}", types.join(" | "));
+        }
         let second = time::Duration::from_millis(1000);
         thread::sleep(second);
     }
@@ -39,138 +86,825 @@ fn main() {
                 .long("udp_port")
                 .takes_value(true)
                 .help("Sets the UDP port to ping")
-                .required(true),
+                .default_value("8125"),
+        )
+        .arg(
+            Arg::with_name("host")
+                .long("host")
+                .takes_value(true)
+                .default_value("0.0.0.0")
+                .validator(|s| s.parse::<Endpoint>().map(|_| ()))
+                .help("Sets the host to ping, a name, an IPv4 or IPv6 address, unix-dgram://path or, with tcp, unix://path"),
+        )
+        .arg(
+            Arg::with_name("transport")
+                .long("transport")
+                .takes_value(true)
+                .possible_values(&["udp", "tcp"])
+                .default_value("udp")
+                .help("Send packets as datagrams or stream newline separated lines"),
+        )
+        .arg(
+            Arg::with_name("tcp_port")
+                .long("tcp_port")
+                .takes_value(true)
+                .default_value("8125")
+                .help("Sets the TCP port to hit"),
+        )
+        .arg(
+            Arg::with_name("connections")
+                .long("connections")
+                .takes_value(true)
+                .default_value("1")
+                .validator(|s| match s.parse::<usize>() {
+                    Ok(n) if n > 0 => Ok(()),
+                    _ => Err(format!("'{}' is not a positive number of connections", s)),
+                })
+                .help("Number of persistent TCP connections per thread"),
+        )
+        .arg(
+            Arg::with_name("reconnect_delay")
+                .long("reconnect_delay")
+                .takes_value(true)
+                .default_value("1000")
+                .help("Milliseconds to wait before reconnecting a dropped TCP connection"),
+        )
+        .arg(
+            Arg::with_name("reconnect_chance")
+                .long("reconnect_chance")
+                .takes_value(true)
+                .default_value("0")
+                .help("Deliberately drop a TCP connection after 1 in N writes, 0 for never"),
+        )
+        .arg(
+            Arg::with_name("spray")
+                .long("spray")
+                .help("Spread packets over every address the host resolves to"),
         )
         .arg(
             Arg::with_name("pool_size")
                 .long("pool_size")
                 .takes_value(true)
                 .help("Total size of potential metric names to emit")
-                .required(true),
+                .required_unless("scenario"),
+        )
+        .arg(
+            Arg::with_name("names")
+                .long("names")
+                .takes_value(true)
+                .default_value("a{random:6}")
+                .validator(|s| s.parse::<Template>().map(|_| ()))
+                .help("Metric name template, like 'svc.{word:50}.{segments:1..3:4..8}.latency'"),
+        )
+        .arg(
+            Arg::with_name("name_charset")
+                .long("name_charset")
+                .takes_value(true)
+                .possible_values(&["ascii", "unicode"])
+                .default_value("ascii")
+                .help("Letters that words and segments in names are made of"),
+        )
+        .arg(
+            Arg::with_name("invalid_names")
+                .long("invalid_names")
+                .takes_value(true)
+                .default_value("0")
+                .help("Percentage of names corrupted with a reserved character"),
+        )
+        .arg(
+            Arg::with_name("churn")
+                .long("churn")
+                .takes_value(true)
+                .validator(|s| s.parse::<Churn>().map(|_| ()))
+                .help("Replace pool entries with new names, N per second as 'N/s' or every M lines as 'N/M'"),
+        )
+        .arg(
+            Arg::with_name("selection")
+                .long("selection")
+                .takes_value(true)
+                .default_value("uniform")
+                .validator(|s| s.parse::<Selection>().map(|_| ()))
+                .help("How pool entries are picked: uniform, zipf:S or hot:P:T for P% taking T%"),
+        )
+        .arg(
+            Arg::with_name("mix")
+                .long("mix")
+                .takes_value(true)
+                .default_value("g=45,c=50,ms=3,h=2")
+                .validator(|s| s.parse::<Mix>().map(|_| ()))
+                .help("Relative weights of metric types in the pool, from g, c, ms, h, s and d"),
+        )
+        .arg(
+            Arg::with_name("values")
+                .long("values")
+                .takes_value(true)
+                .default_value("")
+                .validator(|s| s.parse::<Values>().map(|_| ()))
+                .help("Value distribution per metric type, like 'c=int:1..10,g=walk:100:5,ms=lognormal:3:1'"),
+        )
+        .arg(
+            Arg::with_name("adversarial")
+                .long("adversarial")
+                .takes_value(true)
+                .default_value("0")
+                .help("Percentage of values replaced by NaN, infinities and extremes"),
+        )
+        .arg(
+            Arg::with_name("sample_rate")
+                .long("sample_rate")
+                .takes_value(true)
+                .default_value("")
+                .validator(|s| s.parse::<SampleRates>().map(|_| ()))
+                .help("Sample rate sent with each metric type, like 'c=0.1,ms=0.5'"),
+        )
+        .arg(
+            Arg::with_name("dialect")
+                .long("dialect")
+                .takes_value(true)
+                .possible_values(&["statsd", "dogstatsd"])
+                .default_value("statsd")
+                .help("Plain statsd, or DogStatsD with tags, events and service checks"),
+        )
+        .arg(
+            Arg::with_name("tags")
+                .long("tags")
+                .takes_value(true)
+                .default_value("0")
+                .help("Number of DogStatsD tags on each line"),
+        )
+        .arg(
+            Arg::with_name("tag_cardinality")
+                .long("tag_cardinality")
+                .takes_value(true)
+                .default_value("1")
+                .help("Number of distinct DogStatsD tag combinations for each metric name"),
+        )
+        .arg(
+            Arg::with_name("events")
+                .long("events")
+                .takes_value(true)
+                .default_value("0")
+                .help("Percentage of DogStatsD packets carrying an event"),
+        )
+        .arg(
+            Arg::with_name("service_checks")
+                .long("service_checks")
+                .takes_value(true)
+                .default_value("0")
+                .help("Percentage of DogStatsD packets carrying a service check"),
+        )
+        .arg(
+            Arg::with_name("container_ids")
+                .long("container_ids")
+                .takes_value(true)
+                .default_value("0")
+                .help("Number of distinct DogStatsD container IDs to attribute lines to"),
+        )
+        .arg(
+            Arg::with_name("timestamps")
+                .long("timestamps")
+                .help("Send DogStatsD lines with a timestamp"),
+        )
+        .arg(
+            Arg::with_name("sequence")
+                .long("sequence")
+                .help("Start every packet with a sequence number line, for listeners to account for loss"),
+        )
+        .arg(
+            Arg::with_name("scenario")
+                .long("scenario")
+                .takes_value(true)
+                .help("File of 'option = value' lines describing the workload shape"),
         )
         .arg(
             Arg::with_name("line_limit")
                 .long("line_limit")
                 .takes_value(true)
                 .help("Maximum number of lines to emit in a statsd payload")
-                .required(true),
+                .required_unless_one(&["rate", "profile", "search"]),
         )
         .arg(
             Arg::with_name("delay_limit")
                 .long("delay_limit")
                 .takes_value(true)
                 .help("Total number of milliseconds to wait between emitting payloads")
-                .required(true),
+                .required_unless_one(&["rate", "profile", "search"]),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .takes_value(true)
+                .help("Seed for workload generation, random if not set")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("rate")
+                .long("rate")
+                .takes_value(true)
+                .help("Emit open-loop at a constant rate, in rate_unit per second")
+                .validator(|s| parse_positive_rate(&s).map(|_| ()))
+                .conflicts_with_all(&["line_limit", "delay_limit"])
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("rate_unit")
+                .long("rate_unit")
+                .takes_value(true)
+                .possible_values(&["lines", "packets"])
+                .default_value("lines")
+                .help("Whether rate and profile count lines or packets"),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .takes_value(true)
+                .help("Emit open-loop following phases like '10k@30s,10k..1M@5m,1M@5m'")
+                .validator(|s| s.parse::<Profile>().map(|_| ()))
+                .conflicts_with_all(&["rate", "line_limit", "delay_limit"])
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .long("threads")
+                .takes_value(true)
+                .default_value("1")
+                .validator(|s| match s.parse::<usize>() {
+                    Ok(n) if n > 0 => Ok(()),
+                    _ => Err(format!("'{}' is not a positive number of threads", s)),
+                })
+                .help("Number of sender threads, each with its own socket"),
+        )
+        .arg(
+            Arg::with_name("source_port")
+                .long("source_port")
+                .takes_value(true)
+                .help("First source port to bind, each thread taking the next")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("batch")
+                .long("batch")
+                .takes_value(true)
+                .default_value("1")
+                .help("Number of packets to submit per syscall, using sendmmsg on Linux"),
+        )
+        .arg(
+            Arg::with_name("gso")
+                .long("gso")
+                .help("Send batches with UDP segmentation offload, padding packets with newlines"),
         )
+        .args(&search::args(&["rate", "profile", "line_limit", "delay_limit"]))
         .get_matches();
 
-    let _join = thread::spawn(move || tick());
-
-    let mut rng = thread_rng();
-
     let udp_port = matches
         .value_of("udp_port")
         .unwrap()
         .parse::<u16>()
         .unwrap();
-    let pool_size = matches
-        .value_of("pool_size")
+    let threads = matches
+        .value_of("threads")
         .unwrap()
         .parse::<usize>()
         .unwrap();
+    let scenario = matches
+        .value_of("scenario")
+        .map(|path| {
+            Scenario::load(path, SCENARIO_OPTIONS)
+                .unwrap_or_else(|e| invalid(&format!("scenario {}: {}", path, e)))
+        })
+        .unwrap_or_default();
+    let config = statsd_config(&matches, &scenario, threads).unwrap_or_else(|e| invalid(&e));
     let line_limit = matches
         .value_of("line_limit")
+        .map(|s| s.parse::<usize>().unwrap());
+    let delay_limit = matches
+        .value_of("delay_limit")
+        .map(|s| s.parse::<u64>().unwrap());
+    let profile = match (matches.value_of("rate"), matches.value_of("profile")) {
+        (Some(rate), _) => Some(Profile::constant(parse_positive_rate(rate).unwrap())),
+        (_, Some(profile)) => Some(profile.parse::<Profile>().unwrap()),
+        (None, None) => None,
+    };
+    let pace_lines = matches.value_of("rate_unit") == Some("lines");
+    let trials = Trials::from_matches(&matches);
+    let seed = matches
+        .value_of("seed")
+        .map(|s| s.parse::<u64>().unwrap())
+        .unwrap_or_else(generator::random_seed);
+
+    let source_port = matches
+        .value_of("source_port")
+        .map(|s| s.parse::<u16>().unwrap());
+
+    let batch = matches
+        .value_of("batch")
         .unwrap()
         .parse::<usize>()
         .unwrap();
-    let delay_limit = matches
-        .value_of("delay_limit")
+    let gso = matches.is_present("gso");
+
+    let tcp = matches.value_of("transport") == Some("tcp");
+    let port = if tcp {
+        matches
+            .value_of("tcp_port")
+            .unwrap()
+            .parse::<u16>()
+            .unwrap()
+    } else {
+        udp_port
+    };
+    let connections = matches
+        .value_of("connections")
         .unwrap()
-        .parse::<u64>()
+        .parse::<usize>()
         .unwrap();
+    let reconnect = Reconnect {
+        delay: time::Duration::from_millis(
+            matches
+                .value_of("reconnect_delay")
+                .unwrap()
+                .parse::<u64>()
+                .unwrap(),
+        ),
+        chance: matches
+            .value_of("reconnect_chance")
+            .unwrap()
+            .parse::<u32>()
+            .unwrap(),
+    };
+    if tcp && (batch > 1 || gso) {
+        invalid("batching is only supported over UDP");
+    }
 
-    let addr = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0);
-    let dest = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), udp_port);
-
-    let socket = UdpSocket::bind(addr).unwrap();
-    socket.set_nonblocking(true).unwrap();
-
-    let mut pool: Vec<(String, &str)> = Vec::with_capacity(pool_size);
-    let mut attempts = 10;
-    let mut gauges = 0;
-    let mut counters = 0;
-    let mut histograms = 0;
-    let mut timers = 0;
-    while attempts > 0 {
-        for _ in 0..pool_size {
-            let metric_name: String = rng.gen_ascii_chars().take(6).collect();
-            match pool.binary_search_by(|probe| probe.0.cmp(&metric_name)) {
-                Ok(_) => {}
-                Err(idx) => {
-                    let metric_type: &str = match rng.gen_range(0, 100) {
-                        98...100 => {
-                            histograms += 1;
-                            "h"
-                        }
-                        95...97 => {
-                            timers += 1;
-                            "ms"
-                        }
-                        45...94 => {
-                            counters += 1;
-                            "c"
-                        }
-                        _ => {
-                            gauges += 1;
-                            "g"
-                        }
+    let endpoint = matches.value_of("host").unwrap().parse::<Endpoint>().unwrap();
+    let mut dests = Vec::new();
+    match endpoint {
+        Endpoint::Inet(ref host) => {
+            dests = resolve(host, port);
+            if dests.is_empty() {
+                invalid(&format!("could not resolve {}", host));
+            }
+            if !matches.is_present("spray") {
+                dests.truncate(1);
+            }
+            for dest in &dests {
+                println!("DESTINATION: {}", dest);
+            }
+        }
+        Endpoint::UnixDgram(ref path) => {
+            if tcp || batch > 1 || gso {
+                invalid("unix-dgram:// takes unbatched datagrams");
+            }
+            println!("DESTINATION: unix-dgram://{}", path.display());
+        }
+        Endpoint::Unix(ref path) => {
+            if !tcp {
+                invalid("unix:// is a stream socket, use --transport tcp");
+            }
+            println!("DESTINATION: unix://{}", path.display());
+        }
+    }
+
+    println!("SEED: {}", seed);
+    let gen = StatsdGenerator::new(seed, &config);
+
+    println!("POOL FILLED");
+    for metric_type in MetricType::ALL.iter() {
+        println!(
+            "{:<2}{:<15}{}",
+            "",
+            format!("{}:", metric_type),
+            gen.count(*metric_type)
+        );
+    }
+
+    let mut gens: Vec<StatsdGenerator> = (1..threads).map(|i| gen.fork(i as u64)).collect();
+    gens.insert(0, gen);
+    let mut workers = Vec::with_capacity(threads);
+    for (i, gen) in gens.into_iter().enumerate() {
+        let links = match endpoint {
+            Endpoint::UnixDgram(ref path) => vec![
+                Link::Datagram {
+                    datagram: Datagram::unix(path).unwrap(),
+                    batch: None,
+                },
+            ],
+            Endpoint::Unix(_) => (0..connections)
+                .map(|j| {
+                    let seed = link_seed(seed, i * connections + j);
+                    Link::Stream(Persistent::new(endpoint.clone(), port, reconnect, seed))
+                })
+                .collect(),
+            Endpoint::Inet(_) if tcp => (0..connections)
+                .map(|j| {
+                    let dest = dests[j % dests.len()];
+                    let endpoint = Endpoint::Inet(dest.ip().to_string());
+                    let seed = link_seed(seed, i * connections + j);
+                    Link::Stream(Persistent::new(endpoint, dest.port(), reconnect, seed))
+                })
+                .collect(),
+            Endpoint::Inet(_) => dests
+                .iter()
+                .enumerate()
+                .map(|(j, dest)| {
+                    let port = source_port.map_or(0, |p| p + (i * dests.len() + j) as u16);
+                    let batch = if batch > 1 || gso {
+                        Some(Batch::new(batch, gso))
+                    } else {
+                        None
                     };
-                    pool.insert(idx, (metric_name.clone(), metric_type));
-                }
+                    Link::Datagram {
+                        datagram: Datagram::udp(*dest, port).unwrap(),
+                        batch,
+                    }
+                })
+                .collect(),
+        };
+        // links take turns, so each numbers its own packets
+        let sequences = if matches.is_present("sequence") {
+            (0..links.len())
+                .map(|j| (sequence::metric_name(&sequence::source(i * links.len() + j)), 0))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        workers.push(Worker {
+            id: i,
+            gen,
+            links,
+            next: 0,
+            sequences,
+        });
+    }
+    *TALLIES.lock().unwrap() = vec![(Tally::default(), 0); threads];
+
+    let paced = profile.is_some() || trials.is_some();
+    let churn = config.churn.is_some();
+    let _join = thread::spawn(move || tick(paced, churn));
+
+    if let Some(mut trials) = trials {
+        while let Some(rate) = trials.search.next_rate() {
+            let before = trials.read_feedback();
+            let profile = Profile::constant_for(rate, trials.trial);
+            let (returned, sent, lag) = run(workers, Some(&profile), pace_lines, None);
+            workers = returned;
+            thread::sleep(trials.settle);
+            let after = trials.read_feedback();
+            trials.conclude(rate, sent, lag, before, after);
+        }
+        trials.report();
+        return;
+    }
+
+    let throttle = match (line_limit, delay_limit) {
+        (Some(line_limit), Some(delay_limit)) => Some((line_limit, delay_limit)),
+        _ => None,
+    };
+    let (workers, _, _) = run(workers, profile.as_ref(), pace_lines, throttle);
+    println!("PROFILE COMPLETE");
+
+    let mut tally = Tally::default();
+    let mut retired = 0;
+    for worker in &workers {
+        tally.merge(worker.gen.tally());
+        retired += worker.gen.retired();
+    }
+    println!("RETIRED BY CHURN: {}", retired);
+    println!("EFFECTIVE COUNTS");
+    for metric_type in MetricType::ALL.iter() {
+        println!(
+            "{:<2}{:<15}LINES: {} | EFFECTIVE: {:.1} | NON-FINITE LINES: {}",
+            "",
+            format!("{}:", metric_type),
+            tally.lines(*metric_type),
+            tally.effective(*metric_type),
+            tally.non_finite(*metric_type)
+        );
+    }
+}
+
+/// The options a scenario file may set
+const SCENARIO_OPTIONS: &[&str] = &[
+    "pool_size",
+    "names",
+    "name_charset",
+    "invalid_names",
+    "churn",
+    "selection",
+    "mix",
+    "sample_rate",
+    "values",
+    "adversarial",
+    "dialect",
+    "tags",
+    "tag_cardinality",
+    "events",
+    "service_checks",
+    "container_ids",
+    "timestamps",
+];
+
+/// Exit reporting an invalid option, as clap does for the command line
+fn invalid(description: &str) -> ! {
+    clap::Error::with_description(description, clap::ErrorKind::InvalidValue).exit()
+}
+
+/// The workload shape, from the command line and `scenario`, which clap has
+/// not validated
+fn statsd_config(
+    matches: &ArgMatches,
+    scenario: &Scenario,
+    threads: usize,
+) -> Result<StatsdConfig, String> {
+    // options with defaults always have a value, hence the unwraps
+    let mut config = StatsdConfig {
+        pool_size: scenario.parse_value(matches, "pool_size")?.ok_or_else(|| {
+            "pool_size is required, on the command line or in the scenario".to_string()
+        })?,
+        names: NameConfig {
+            template: scenario.parse_value(matches, "names")?.unwrap(),
+            charset: scenario.parse_value(matches, "name_charset")?.unwrap(),
+            invalid: scenario.parse_value(matches, "invalid_names")?.unwrap(),
+        },
+        churn: scenario
+            .parse_value::<Churn>(matches, "churn")?
+            .map(|churn| churn.scaled(1.0 / threads as f64)),
+        selection: scenario.parse_value(matches, "selection")?.unwrap(),
+        mix: scenario.parse_value(matches, "mix")?.unwrap(),
+        sample_rates: scenario.parse_value(matches, "sample_rate")?.unwrap(),
+        dogstatsd: None,
+        values: scenario.parse_value(matches, "values")?.unwrap(),
+        adversarial: scenario.parse_value(matches, "adversarial")?.unwrap(),
+    };
+    match scenario.value(matches, "dialect").unwrap().as_str() {
+        "statsd" => {}
+        "dogstatsd" => {
+            let dogstatsd = DogStatsd {
+                tags: scenario.parse_value(matches, "tags")?.unwrap(),
+                tag_cardinality: scenario.parse_value(matches, "tag_cardinality")?.unwrap(),
+                events: scenario.parse_value(matches, "events")?.unwrap(),
+                service_checks: scenario.parse_value(matches, "service_checks")?.unwrap(),
+                container_ids: scenario.parse_value(matches, "container_ids")?.unwrap(),
+                timestamps: scenario.is_present(matches, "timestamps")?,
             };
+            if dogstatsd.events + dogstatsd.service_checks > 100 {
+                return Err("events and service_checks add up to more than 100%".into());
+            }
+            config.dogstatsd = Some(dogstatsd);
         }
-        if pool.len() == pool_size {
-            break;
+        dialect => {
+            return Err(format!(
+                "invalid dialect '{}': expected statsd or dogstatsd",
+                dialect
+            ))
         }
-        attempts -= 1;
     }
+    Ok(config)
+}
+
+/// A sender thread's private state
+struct Worker {
+    id: usize,
+    gen: StatsdGenerator,
+    links: Vec<Link>,
+    next: usize,
+    // per link, the sequence metric's name and the next number to send,
+    // empty unless numbering packets
+    sequences: Vec<(String, u64)>,
+}
+
+/// A socket bound for one destination
+enum Link {
+    Datagram {
+        datagram: Datagram,
+        batch: Option<Batch>,
+    },
+    Stream(Persistent),
+}
 
-    let mut vals = Vec::with_capacity(1000);
-    for i in 0..1000 {
-        vals.push(i.to_string());
+impl Link {
+    /// Send, or batch, one packet of `lines` lines, updating the global
+    /// accounting. Returns false if the packet could not be delivered.
+    fn send(&mut self, buf: &[u8], lines: usize) -> bool {
+        match *self {
+            Link::Datagram {
+                batch: Some(ref mut batch),
+                ..
+            } => {
+                batch.push(buf, lines);
+                if !batch.is_full() {
+                    return true;
+                }
+            }
+            Link::Datagram {
+                ref datagram,
+                batch: None,
+            } => {
+                // a socket that would block is retried, as batches are
+                loop {
+                    SYSCALLS.fetch_add(1, Ordering::Relaxed);
+                    match datagram.send(buf) {
+                        Ok(_) => break,
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::yield_now(),
+                        Err(e) => panic!("{}", e),
+                    }
+                }
+                LINES_WRITTEN.fetch_add(lines, Ordering::Relaxed);
+                PACKETS_WRITTEN.fetch_add(1, Ordering::Relaxed);
+                return true;
+            }
+            Link::Stream(ref mut persistent) => {
+                SYSCALLS.fetch_add(1, Ordering::Relaxed);
+                if !persistent.send(buf) {
+                    return false;
+                }
+                LINES_WRITTEN.fetch_add(lines, Ordering::Relaxed);
+                PACKETS_WRITTEN.fetch_add(1, Ordering::Relaxed);
+                return true;
+            }
+        }
+        self.flush();
+        true
     }
 
-    println!("POOL FILLED");
-    println!("{:<2}GAUGES:     {}", "", gauges);
-    println!("{:<2}COUNTERS:   {}", "", counters);
-    println!("{:<2}HISTOGRAMS: {}", "", histograms);
-    println!("{:<2}TIMERS:     {}", "", timers);
+    /// Send everything batched, updating the global accounting
+    fn flush(&mut self) {
+        if let Link::Datagram {
+            batch: Some(ref mut batch),
+            datagram: Datagram::Udp {
+                ref socket,
+                ref dest,
+            },
+        } = *self
+        {
+            let segmented = batch.is_segmented();
+            let sent = batch.send(socket, dest).unwrap();
+            if segmented && !batch.is_segmented() {
+                println!("UDP_SEGMENT UNSUPPORTED, FALLING BACK TO SENDMMSG");
+            }
+            LINES_WRITTEN.fetch_add(sent.lines, Ordering::Relaxed);
+            PACKETS_WRITTEN.fetch_add(sent.datagrams, Ordering::Relaxed);
+            SYSCALLS.fetch_add(sent.syscalls, Ordering::Relaxed);
+        }
+    }
+}
 
+/// How a dropped stream connection is re-established
+#[derive(Clone, Copy)]
+struct Reconnect {
+    delay: time::Duration,
+    chance: u32,
+}
+
+/// A stream connection that, like native_emitter's, is re-established
+/// after a delay whenever a write fails or it is deliberately dropped
+struct Persistent {
+    endpoint: Endpoint,
+    port: u16,
+    stream: Option<Stream>,
+    reconnect: Reconnect,
+    attempted: bool,
+    // draws deliberate drops, seeded so that runs drop alike
+    rng: XorShiftRng,
+}
+
+impl Persistent {
+    fn new(endpoint: Endpoint, port: u16, reconnect: Reconnect, seed: u64) -> Persistent {
+        Persistent {
+            endpoint,
+            port,
+            stream: None,
+            reconnect,
+            attempted: false,
+            rng: generator::seeded_rng(seed),
+        }
+    }
+
+    /// Write `buf`, connecting first if need be. Returns false if `buf`
+    /// could not be written.
+    fn send(&mut self, buf: &[u8]) -> bool {
+        if self.stream.is_none() {
+            if self.attempted {
+                thread::sleep(self.reconnect.delay);
+            }
+            self.attempted = true;
+            self.stream = connect(&self.endpoint, self.port);
+        }
+        let delivered = match self.stream {
+            Some(ref mut stream) => stream.write_all(buf).is_ok(),
+            None => false,
+        };
+        let chance = self.reconnect.chance;
+        if !delivered || (chance > 0 && self.rng.gen_weighted_bool(chance)) {
+            self.stream = None;
+        }
+        delivered
+    }
+}
+
+/// The seed of stream link `link`'s drops, apart from every generator's
+fn link_seed(seed: u64, link: usize) -> u64 {
+    generator::stream_seed(seed, u64::MAX - link as u64)
+}
+
+/// Publish `worker`'s tally and churn retirements for `tick` to report
+fn publish(worker: &Worker) {
+    TALLIES.lock().unwrap()[worker.id] = (worker.gen.tally().clone(), worker.gen.retired());
+}
+
+/// Run each worker on its own thread until `profile` runs out, the
+/// profile's rate being split evenly between them. Returns the workers, the
+/// total units sent and the furthest behind schedule any worker fell.
+fn run(
+    workers: Vec<Worker>,
+    profile: Option<&Profile>,
+    pace_lines: bool,
+    throttle: Option<(usize, u64)>,
+) -> (Vec<Worker>, usize, time::Duration) {
+    let share = workers.len() as f64;
+    let handles: Vec<_> = workers
+        .into_iter()
+        .map(|mut worker| {
+            let pacer = profile.map(|p| Pacer::with_profile(p.scaled(1.0 / share)));
+            thread::spawn(move || {
+                let (sent, lag) = emit(&mut worker, pacer, pace_lines, throttle, share);
+                (worker, sent, lag)
+            })
+        })
+        .collect();
+
+    let mut workers = Vec::with_capacity(handles.len());
+    let mut sent = 0;
+    let mut max_lag = time::Duration::from_millis(0);
+    for handle in handles {
+        let (worker, worker_sent, worker_lag) = handle.join().unwrap();
+        workers.push(worker);
+        sent += worker_sent;
+        max_lag = max_lag.max(worker_lag);
+    }
+    (workers, sent, max_lag)
+}
+
+/// Emit until the pacer's profile runs out, returning the number of units
+/// sent and the furthest behind schedule the emitter fell. Without a pacer
+/// this never returns.
+fn emit(
+    worker: &mut Worker,
+    mut pacer: Option<Pacer>,
+    pace_lines: bool,
+    throttle: Option<(usize, u64)>,
+    share: f64,
+) -> (usize, time::Duration) {
     let mut buf = String::new();
+    let mut sent = 0;
+    let mut max_lag = time::Duration::from_millis(0);
+    let mut published = time::Instant::now();
     loop {
-        let choice = rng.choose(&pool).unwrap();
-        let metric_name = &choice.0;
-        let metric_type = &choice.1;
-        let val = rng.choose(&vals).unwrap();
-
-        let tot = rng.gen_range(1, 40);
-        let lines_written = LINES_WRITTEN.fetch_add(tot, Ordering::Relaxed);
-        for _ in 0..tot {
-            buf.push_str("a");
-            buf.push_str(metric_name);
-            buf.push_str(":");
-            buf.push_str(val);
-            buf.push_str("|");
-            buf.push_str(metric_type);
-            buf.push_str("\n");
-        }
-        PACKETS_WRITTEN.fetch_add(1, Ordering::Relaxed);
-        socket.send_to(buf.as_bytes(), dest).unwrap();
+        let tot = worker.gen.fill_packet(&mut buf);
+        if published.elapsed() >= time::Duration::from_millis(1000) {
+            publish(worker);
+            published = time::Instant::now();
+        }
+        let units = if pace_lines { tot } else { 1 };
+        if let Some(ref mut pacer) = pacer {
+            // batched packets were due when batched, so go out before a wait
+            if pacer.would_wait(units) {
+                for link in &mut worker.links {
+                    link.flush();
+                }
+            }
+            let lag = match pacer.pace(units) {
+                Some(lag) => lag,
+                None => {
+                    for link in &mut worker.links {
+                        link.flush();
+                    }
+                    publish(worker);
+                    return (sent, max_lag);
+                }
+            };
+            max_lag = max_lag.max(lag);
+            MAX_LAG_US.fetch_max(lag.as_micros() as usize, Ordering::Relaxed);
+            let target = pacer.target_rate().unwrap_or(0.0) * share;
+            TARGET_RATE.store(target as usize, Ordering::Relaxed);
+        }
+        let idx = worker.next;
+        worker.next = (idx + 1) % worker.links.len();
+        if let Some(&mut (ref name, ref mut seq)) = worker.sequences.get_mut(idx) {
+            buf.insert_str(0, &format!("{}:{}|g\n", name, seq));
+            *seq += 1;
+        }
+        if worker.links[idx].send(buf.as_bytes(), tot) {
+            sent += units;
+        }
+        let lines_written = LINES_WRITTEN.load(Ordering::Relaxed);
         buf.clear();
-        if lines_written > line_limit {
-            use std::time;
-            let slp = time::Duration::from_millis(delay_limit as u64);
-            thread::sleep(slp);
+        if let Some((line_limit, delay_limit)) = throttle {
+            if lines_written > line_limit {
+                let slp = time::Duration::from_millis(delay_limit);
+                thread::sleep(slp);
+            }
         }
     }
 }
+
@@ -1,76 +1,97 @@
-extern crate byteorder;
 extern crate clap;
 #[macro_use]
 extern crate lazy_static;
 extern crate llrv;
-extern crate protobuf;
 extern crate rand;
 
 use clap::{App, Arg};
-use rand::{thread_rng, Rng};
+use llrv::generator;
+use llrv::generator::bins::parse_bound_sets;
+use llrv::generator::churn::Churn;
+use llrv::generator::logs::{LogConfig, Template as LogTemplate};
+use llrv::generator::metadata::MetadataConfig;
+use llrv::generator::names::{Charset, NameConfig, Template};
+use llrv::generator::native::{NativeConfig, NativeGenerator, Values};
+use llrv::generator::selection::Selection;
+use llrv::generator::timestamps::Timestamps;
+use llrv::generator::values::Distribution;
+use llrv::pacer::Pacer;
+use llrv::profile::{parse_positive_rate, Profile};
+use llrv::protocols::native::codec::write_frame;
+use llrv::protocols::native::{Payload, Telemetry};
+use llrv::search::{self, Trials};
+use llrv::sequence;
+use llrv::transport::{connect, Endpoint, Stream};
+use rand::{Rng, XorShiftRng};
+use std::str::FromStr;
 use std::sync::Arc;
-use std::net::{TcpStream, ToSocketAddrs};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time;
-use std::io::BufWriter;
-use byteorder::{BigEndian, ByteOrder};
 use std::thread;
-use llrv::protocols::native::*;
-use protobuf::Message;
-use protobuf::repeated::RepeatedField;
-use protobuf::stream::CodedOutputStream;
 
 lazy_static! {
     static ref LINES_WRITTEN: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
     static ref PACKETS_WRITTEN: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+    static ref MAX_LAG_US: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+    static ref TARGET_RATE: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+    static ref RETIRED: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
 }
 
-fn tick() {
+fn tick(paced: bool, churn: bool) {
     loop {
         let packets = PACKETS_WRITTEN.swap(0, Ordering::Relaxed);
         let lines = LINES_WRITTEN.swap(0, Ordering::Relaxed);
-        println!(
-            "LINES PER SECOND: {} | TOTAL PACKETS PER SECOND: {}",
-            lines, packets
-        );
+        if paced {
+            let lag = MAX_LAG_US.swap(0, Ordering::Relaxed);
+            let target = TARGET_RATE.load(Ordering::Relaxed);
+            println!(
+                "LINES PER SECOND: {} | TOTAL PACKETS PER SECOND: {} | TARGET RATE: {} | MAX BEHIND SCHEDULE: {}us",
+                lines, packets, target, lag
+            );
+        } else {
+            println!(
+                "LINES PER SECOND: {} | TOTAL PACKETS PER SECOND: {}",
+                lines, packets
+            );
+        }
+        if churn {
+            println!(
+                "  TOTAL RETIRED BY CHURN: {}",
+                RETIRED.load(Ordering::Relaxed)
+            );
+        }
         let second = time::Duration::from_millis(1000);
         thread::sleep(second);
     }
 }
 
-fn connect(host: &str, port: u16) -> Option<TcpStream> {
-    if let Ok(srv) = (host, port).to_socket_addrs() {
-        let ips: Vec<_> = srv.collect();
-        for ip in ips {
-            if let Ok(stream) = TcpStream::connect(ip) {
-                return Some(stream);
-            }
-        }
-    }
-    None
-}
-
 fn main() {
-    let matches = App::new("llrv")
+    let matches = App::new("native_emitter")
         .about("stresses cernan native servers")
         .arg(
             Arg::with_name("port")
                 .long("port")
                 .takes_value(true)
+                .validator(number::<u16>)
                 .help("Sets the port to hit")
-                .required(true),
+                .default_value("1972"),
         )
         .arg(
             Arg::with_name("host")
                 .long("host")
                 .takes_value(true)
-                .help("Sets the host to hit")
+                .help("Sets the host to hit, a name, an IPv4 or IPv6 address or unix://path")
+                .validator(|s| match s.parse::<Endpoint>()? {
+                    Endpoint::UnixDgram(_) => Err("native needs a unix:// socket".into()),
+                    _ => Ok(()),
+                })
                 .required(true),
         )
         .arg(
             Arg::with_name("pool_size")
                 .long("pool_size")
                 .takes_value(true)
+                .validator(positive)
                 .help("Total size of potential metric names to emit")
                 .required(true),
         )
@@ -78,121 +99,487 @@ fn main() {
             Arg::with_name("payload_limit")
                 .long("payload_limit")
                 .takes_value(true)
+                .validator(positive)
                 .help("Maximum number of points to emit in a payload")
                 .required(true),
         )
+        .arg(
+            Arg::with_name("names")
+                .long("names")
+                .takes_value(true)
+                .default_value("{random:6}")
+                .validator(|s| s.parse::<Template>().map(|_| ()))
+                .help("Metric name template, like 'svc.{word:50}.{segments:1..3:4..8}.latency'"),
+        )
+        .arg(
+            Arg::with_name("name_charset")
+                .long("name_charset")
+                .takes_value(true)
+                .possible_values(&["ascii", "unicode"])
+                .default_value("ascii")
+                .help("Letters that words and segments in names are made of"),
+        )
+        .arg(
+            Arg::with_name("invalid_names")
+                .long("invalid_names")
+                .takes_value(true)
+                .validator(percentage)
+                .default_value("0")
+                .help("Percentage of names corrupted with a reserved character"),
+        )
+        .arg(
+            Arg::with_name("churn")
+                .long("churn")
+                .takes_value(true)
+                .validator(|s| s.parse::<Churn>().map(|_| ()))
+                .help("Replace pool entries with new names, N per second as 'N/s' or every M points as 'N/M'"),
+        )
+        .arg(
+            Arg::with_name("selection")
+                .long("selection")
+                .takes_value(true)
+                .default_value("uniform")
+                .validator(|s| s.parse::<Selection>().map(|_| ()))
+                .help("How pool entries are picked: uniform, zipf:S or hot:P:T for P% taking T%"),
+        )
+        .arg(
+            Arg::with_name("values")
+                .long("values")
+                .takes_value(true)
+                .default_value("")
+                .validator(|s| s.parse::<Values>().map(|_| ()))
+                .help("Value distribution per aggregation method, like 'summarize=lognormal:0:1,sum=monotonic:10'"),
+        )
+        .arg(
+            Arg::with_name("adversarial")
+                .long("adversarial")
+                .takes_value(true)
+                .validator(percentage)
+                .default_value("0")
+                .help("Percentage of values replaced by NaN, infinities and extremes"),
+        )
+        .arg(
+            Arg::with_name("bins")
+                .long("bins")
+                .takes_value(true)
+                .validator(|s| parse_bound_sets(&s).map(|_| ()))
+                .help("Bin bound sets for BIN metrics, like 'linear:0:10:10;exp:1:2:16+unsorted'"),
+        )
+        .arg(
+            Arg::with_name("metadata_keys")
+                .long("metadata_keys")
+                .takes_value(true)
+                .validator(number::<usize>)
+                .default_value("0")
+                .help("Number of metadata keys on each point"),
+        )
+        .arg(
+            Arg::with_name("metadata_key_cardinality")
+                .long("metadata_key_cardinality")
+                .takes_value(true)
+                .validator(number::<usize>)
+                .default_value("1")
+                .help("Number of distinct metadata key names"),
+        )
+        .arg(
+            Arg::with_name("metadata_value_cardinality")
+                .long("metadata_value_cardinality")
+                .takes_value(true)
+                .validator(number::<usize>)
+                .default_value("1")
+                .help("Number of distinct values of each metadata key"),
+        )
+        .arg(
+            Arg::with_name("metadata_fixed")
+                .long("metadata_fixed")
+                .help("Keep the same metadata for each metric rather than drawing it per point"),
+        )
+        .arg(
+            Arg::with_name("timestamps")
+                .long("timestamps")
+                .takes_value(true)
+                .validator(|s| s.parse::<Timestamps>().map(|_| ()))
+                .help("Stamp points and log lines, like 'now' or 'lag:2m,jitter:500ms,disorder:10:5s,duplicate:5'"),
+        )
+        .arg(
+            Arg::with_name("log_ratio")
+                .long("log_ratio")
+                .takes_value(true)
+                .validator(percentage)
+                .default_value("0")
+                .help("Percentage of payload entries that are log lines rather than points"),
+        )
+        .arg(
+            Arg::with_name("log_template")
+                .long("log_template")
+                .takes_value(true)
+                .possible_values(&["access", "json", "stacktrace", "plain", "mixed"])
+                .default_value("plain")
+                .help("Shape of log lines"),
+        )
+        .arg(
+            Arg::with_name("log_length")
+                .long("log_length")
+                .takes_value(true)
+                .default_value("int:20..200")
+                .validator(|s| s.parse::<Distribution>().map(|_| ()))
+                .help("Distribution of the length of log lines' free text, like 'lognormal:4:1'"),
+        )
+        .arg(
+            Arg::with_name("log_paths")
+                .long("log_paths")
+                .takes_value(true)
+                .validator(number::<usize>)
+                .default_value("1")
+                .help("Number of distinct paths log lines are written to"),
+        )
+        .arg(
+            Arg::with_name("log_metadata_keys")
+                .long("log_metadata_keys")
+                .takes_value(true)
+                .validator(number::<usize>)
+                .default_value("0")
+                .help("Number of metadata keys on each log line, drawn like points' metadata"),
+        )
+        .arg(
+            Arg::with_name("sequence")
+                .long("sequence")
+                .help("Add a sequence number point to every payload, for listeners to account for loss"),
+        )
         .arg(
             Arg::with_name("delay_limit")
                 .long("delay_limit")
                 .takes_value(true)
+                .validator(number::<u64>)
                 .help("Total number of milliseconds to wait between emitting payloads")
                 .required(true),
         )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .takes_value(true)
+                .validator(number::<u64>)
+                .help("Seed for workload generation, random if not set")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("rate")
+                .long("rate")
+                .takes_value(true)
+                .help("Emit open-loop at a constant rate, in rate_unit per second")
+                .validator(|s| parse_positive_rate(&s).map(|_| ()))
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .takes_value(true)
+                .help("Emit open-loop following phases like '10k@30s,10k..1M@5m,1M@5m'")
+                .validator(|s| s.parse::<Profile>().map(|_| ()))
+                .conflicts_with("rate")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("rate_unit")
+                .long("rate_unit")
+                .takes_value(true)
+                .possible_values(&["points", "payloads"])
+                .default_value("points")
+                .help("Whether rate and profile count points and log lines, or payloads"),
+        )
+        .args(&search::args(&["rate", "profile"]))
         .get_matches();
 
-    let _join = thread::spawn(move || tick());
-
-    let mut rng = thread_rng();
-
-    let host = matches.value_of("host").unwrap();
+    let host = matches.value_of("host").unwrap().parse::<Endpoint>().unwrap();
     let port = matches.value_of("port").unwrap().parse::<u16>().unwrap();
-    let pool_size = matches
-        .value_of("pool_size")
-        .unwrap()
-        .parse::<usize>()
-        .unwrap();
-    let payload_limit = matches
-        .value_of("payload_limit")
+    let metadata = MetadataConfig {
+        keys: matches
+            .value_of("metadata_keys")
+            .unwrap()
+            .parse::<usize>()
+            .unwrap(),
+        key_cardinality: matches
+            .value_of("metadata_key_cardinality")
+            .unwrap()
+            .parse::<usize>()
+            .unwrap(),
+        value_cardinality: matches
+            .value_of("metadata_value_cardinality")
+            .unwrap()
+            .parse::<usize>()
+            .unwrap(),
+        fixed: matches.is_present("metadata_fixed"),
+    };
+    let log_ratio = matches
+        .value_of("log_ratio")
         .unwrap()
         .parse::<u32>()
         .unwrap();
+    let logs = LogConfig {
+        paths: matches
+            .value_of("log_paths")
+            .unwrap()
+            .parse::<usize>()
+            .unwrap(),
+        template: matches
+            .value_of("log_template")
+            .unwrap()
+            .parse::<LogTemplate>()
+            .unwrap(),
+        length: matches
+            .value_of("log_length")
+            .unwrap()
+            .parse::<Distribution>()
+            .unwrap(),
+        metadata: MetadataConfig {
+            keys: matches
+                .value_of("log_metadata_keys")
+                .unwrap()
+                .parse::<usize>()
+                .unwrap(),
+            ..metadata.clone()
+        },
+    };
+    let config = NativeConfig {
+        pool_size: matches
+            .value_of("pool_size")
+            .unwrap()
+            .parse::<usize>()
+            .unwrap(),
+        payload_limit: matches
+            .value_of("payload_limit")
+            .unwrap()
+            .parse::<u32>()
+            .unwrap(),
+        names: NameConfig {
+            template: matches
+                .value_of("names")
+                .unwrap()
+                .parse::<Template>()
+                .unwrap(),
+            charset: matches
+                .value_of("name_charset")
+                .unwrap()
+                .parse::<Charset>()
+                .unwrap(),
+            invalid: matches
+                .value_of("invalid_names")
+                .unwrap()
+                .parse::<u32>()
+                .unwrap(),
+        },
+        churn: matches
+            .value_of("churn")
+            .map(|s| s.parse::<Churn>().unwrap()),
+        selection: matches
+            .value_of("selection")
+            .unwrap()
+            .parse::<Selection>()
+            .unwrap(),
+        values: matches
+            .value_of("values")
+            .unwrap()
+            .parse::<Values>()
+            .unwrap(),
+        adversarial: matches
+            .value_of("adversarial")
+            .unwrap()
+            .parse::<u32>()
+            .unwrap(),
+        bins: matches
+            .value_of("bins")
+            .map(|s| parse_bound_sets(s).unwrap())
+            .unwrap_or_default(),
+        metadata,
+        timestamps: matches
+            .value_of("timestamps")
+            .map(|s| s.parse::<Timestamps>().unwrap()),
+        logs: if log_ratio > 0 {
+            Some((logs, log_ratio))
+        } else {
+            None
+        },
+    };
     let delay_limit = matches
         .value_of("delay_limit")
         .unwrap()
         .parse::<u64>()
         .unwrap();
 
-    let types = [
-        AggregationMethod::BIN,
-        AggregationMethod::SET,
-        AggregationMethod::SUM,
-        AggregationMethod::SUMMARIZE,
-    ];
-
-    let mut pool: Vec<(String, AggregationMethod, bool)> = Vec::with_capacity(pool_size);
-    let mut attempts = 10;
-    while attempts > 0 {
-        for _ in 0..pool_size {
-            let metric_name: String = rng.gen_ascii_chars().take(6).collect();
-            match pool.binary_search_by(|probe| probe.0.cmp(&metric_name)) {
-                Ok(_) => {}
-                Err(idx) => {
-                    let metric_type: &AggregationMethod = rng.choose(&types).unwrap();
-                    let persist: bool = rng.gen::<bool>();
-                    pool.insert(idx, (metric_name.clone(), *metric_type, persist));
-                }
-            };
-        }
-        if pool.len() == pool_size {
-            break;
+    let seed = matches
+        .value_of("seed")
+        .map(|s| s.parse::<u64>().unwrap())
+        .unwrap_or_else(generator::random_seed);
+
+    println!("SEED: {}", seed);
+    let mut gen = NativeGenerator::new(seed, &config);
+
+    let mut pacer = match (matches.value_of("rate"), matches.value_of("profile")) {
+        (Some(rate), _) => Some(Pacer::new(parse_positive_rate(rate).unwrap())),
+        (_, Some(profile)) => Some(Pacer::with_profile(profile.parse::<Profile>().unwrap())),
+        (None, None) => None,
+    };
+    let pace_points = matches.value_of("rate_unit") == Some("points");
+    let trials = Trials::from_matches(&matches);
+
+    println!("POOL FILLED");
+
+    let paced = pacer.is_some() || trials.is_some();
+    let churn = matches.is_present("churn");
+    let _join = thread::spawn(move || tick(paced, churn));
+
+    let mut conn = Connection::new(host, port, delay_limit, seed);
+    let mut sequence = if matches.is_present("sequence") {
+        Some((sequence::metric_name(&sequence::source(0)), 0))
+    } else {
+        None
+    };
+    if let Some(mut trials) = trials {
+        while let Some(rate) = trials.search.next_rate() {
+            let before = trials.read_feedback();
+            let mut pacer = Some(Pacer::with_profile(Profile::constant_for(rate, trials.trial)));
+            let (sent, lag) = emit(
+                &mut gen,
+                &mut conn,
+                &mut pacer,
+                pace_points,
+                &mut sequence,
+            );
+            thread::sleep(trials.settle);
+            let after = trials.read_feedback();
+            trials.conclude(rate, sent, lag, before, after);
         }
-        attempts -= 1;
+        trials.report();
+        return;
     }
 
-    println!("POOL FILLED");
+    emit(
+        &mut gen,
+        &mut conn,
+        &mut pacer,
+        pace_points,
+        &mut sequence,
+    );
+    println!("PROFILE COMPLETE");
+    println!("RETIRED BY CHURN: {}", gen.retired());
+}
 
-    let mut stream = None;
-    loop {
-        let mut points = Vec::new();
-        loop {
-            let choice = rng.choose(&pool).unwrap();
-            let metric_name = &choice.0;
-            let metric_type = &choice.1;
-            let metric_persist = &choice.2;
-
-            let mut point = Telemetry::new();
-            point.set_name(metric_name.to_string());
-            point.set_persisted(*metric_persist);
-            point.set_method(*metric_type);
-            let mut vals = Vec::new();
-            for _ in 0..rng.gen_range(0, 50) {
-                vals.push(rng.gen::<f64>());
-            }
-            point.set_samples(vals);
+/// A connection to the server, re-established after a delay whenever a
+/// write fails or it is deliberately dropped
+///
+/// Drops are drawn from a seeded generator and a payload that could not be
+/// written is resent on the next connection, so that a seed always puts
+/// the same payloads on the wire.
+struct Connection {
+    host: Endpoint,
+    port: u16,
+    delay_limit: u64,
+    stream: Option<Stream>,
+    rng: XorShiftRng,
+    // a payload made but not yet written, its units and whether it has
+    // been paced
+    pending: Option<(Payload, usize, bool)>,
+}
 
-            points.push(point);
+impl Connection {
+    fn new(host: Endpoint, port: u16, delay_limit: u64, seed: u64) -> Connection {
+        Connection {
+            host,
+            port,
+            delay_limit,
+            stream: None,
+            // a stream of its own, leaving the generator's draws untouched
+            rng: generator::seeded_rng(generator::stream_seed(seed, u64::MAX)),
+            pending: None,
+        }
+    }
+}
+
+/// Emit until the pacer's profile runs out, returning the number of units
+/// sent and the furthest behind schedule the emitter fell. Without a pacer
+/// this never returns.
+fn emit(
+    gen: &mut NativeGenerator,
+    conn: &mut Connection,
+    pacer: &mut Option<Pacer>,
+    pace_points: bool,
+    sequence: &mut Option<(String, u64)>,
+) -> (usize, time::Duration) {
+    let mut sent = 0;
+    let mut max_lag = time::Duration::from_millis(0);
+    loop {
+        if conn.stream.is_none() {
+            let slp = time::Duration::from_millis(conn.delay_limit);
+            thread::sleep(slp);
+            conn.stream = connect(&conn.host, conn.port);
+            continue;
+        }
 
-            if rng.gen_weighted_bool(payload_limit) {
-                break;
+        if conn.pending.is_none() {
+            let mut pyld = gen.next_payload();
+            RETIRED.store(gen.retired(), Ordering::Relaxed);
+            let entries = pyld.get_points().len() + pyld.get_lines().len();
+            LINES_WRITTEN.fetch_add(entries, Ordering::Relaxed);
+            if let Some((ref name, ref mut seq)) = *sequence {
+                let mut point = Telemetry::new();
+                point.set_name(name.clone());
+                point.set_samples(vec![*seq as f64]);
+                pyld.mut_points().push(point);
+                *seq += 1;
             }
+            let units = if pace_points { entries } else { 1 };
+            conn.pending = Some((pyld, units, false));
         }
-        LINES_WRITTEN.fetch_add(points.len(), Ordering::Relaxed);
-
-        let mut pyld = Payload::new();
-        pyld.set_points(RepeatedField::from_vec(points));
-
-        let mut delivery_failure = false;
-        if let Some(ref mut strm) = stream {
-            let mut bufwrite = BufWriter::new(strm);
-            let mut strm = CodedOutputStream::new(&mut bufwrite);
-            let mut sz_buf = [0; 4];
-            let pyld_len = pyld.compute_size();
-            BigEndian::write_u32(&mut sz_buf, pyld_len);
-            strm.write_raw_bytes(&sz_buf).unwrap();
-            let res = pyld.write_to_with_cached_sizes(&mut strm);
-            if res.is_err() {
-                delivery_failure = true;
-            } else {
-                PACKETS_WRITTEN.fetch_add(1, Ordering::Relaxed);
+        let (pyld, units, paced) = conn.pending.take().unwrap();
+        if !paced {
+            if let Some(ref mut pacer) = *pacer {
+                let lag = match pacer.pace(units) {
+                    Some(lag) => lag,
+                    None => {
+                        // sent first thing if emission resumes
+                        conn.pending = Some((pyld, units, false));
+                        return (sent, max_lag);
+                    }
+                };
+                max_lag = max_lag.max(lag);
+                MAX_LAG_US.fetch_max(lag.as_micros() as usize, Ordering::Relaxed);
+                let target = pacer.target_rate().unwrap_or(0.0);
+                TARGET_RATE.store(target as usize, Ordering::Relaxed);
             }
-        } else {
-            use std::time;
-            let slp = time::Duration::from_millis(delay_limit as u64);
-            thread::sleep(slp);
-            stream = connect(&host, port);
         }
-        if delivery_failure || rng.gen_weighted_bool(128) {
-            stream = None
+
+        if write_frame(conn.stream.as_mut().unwrap(), &pyld).is_err() {
+            conn.stream = None;
+            conn.pending = Some((pyld, units, true));
+            continue;
+        }
+        PACKETS_WRITTEN.fetch_add(1, Ordering::Relaxed);
+        sent += units;
+        if conn.rng.gen_weighted_bool(128) {
+            conn.stream = None
         }
     }
 }
+
+/// Validate a number of type `T`
+fn number<T: FromStr>(s: String) -> Result<(), String> {
+    s.parse::<T>()
+        .map(|_| ())
+        .map_err(|_| format!("'{}' is not a valid number", s))
+}
+
+/// Validate a positive number, as of pool entries
+fn positive(s: String) -> Result<(), String> {
+    match s.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err(format!("'{}' is not a positive number", s)),
+    }
+}
+
+/// Validate a percentage
+fn percentage(s: String) -> Result<(), String> {
+    match s.parse::<u32>() {
+        Ok(p) if p <= 100 => Ok(()),
+        _ => Err(format!("'{}' is not a percentage from 0 to 100", s)),
+    }
+}
@@ -1,55 +1,289 @@
-extern crate byteorder;
+extern crate clap;
+#[macro_use]
+extern crate lazy_static;
 extern crate llrv;
-extern crate protobuf;
 extern crate rand;
 
-use std::net::{TcpListener, TcpStream};
-use byteorder::BigEndian;
-use byteorder::ReadBytesExt;
-use std::io::Read;
-use llrv::protocols::native::Payload;
+use clap::{App, Arg};
+use std::fs;
+use llrv::profile::parse_duration;
+use llrv::protocols::native::codec::{Decoder, FrameError, DEFAULT_MAX_FRAME};
+use llrv::sequence::{self, Sequences};
+use llrv::transport::{Endpoint, Listener, Stream};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
-use std::io;
+use std::time;
 
-fn handle_client(stream: TcpStream) {
-    let mut buf = Vec::with_capacity(4000);
-    let mut reader = io::BufReader::new(stream);
+lazy_static! {
+    static ref PAYLOADS_RECEIVED: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+    static ref POINTS_RECEIVED: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+    static ref LINES_RECEIVED: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+    static ref BYTES_RECEIVED: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+    static ref FRAME_ERRORS: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+    static ref PAYLOADS_SEEN: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+    static ref SEQUENCES: Mutex<Sequences> = Mutex::new(Sequences::default());
+}
 
-    loop {
-        println!("LISTENER LOOP");
-        let payload_size_in_bytes = match reader.read_u32::<BigEndian>() {
-            Ok(i) => i as usize,
-            Err(_) => {
-                println!("READ PAYLOAD SIZE WAS ERROR");
-                return;
-            }
-        };
-        println!("PAYLOAD SIZE: {:?}", payload_size_in_bytes);
-        buf.resize(payload_size_in_bytes, 0);
-        if reader.read_exact(&mut buf).is_err() {
-            println!("READ EXACT WAS ERROR");
-            return;
+/// What to do with a connection after a frame fails to decode
+#[derive(Debug, Clone, Copy)]
+enum OnError {
+    /// Close the connection
+    Disconnect,
+    /// Trust the size prefix and drop the frame
+    Skip,
+    /// Drop bytes one at a time until a frame decodes again
+    Resync,
+}
+
+impl FromStr for OnError {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OnError, String> {
+        match s {
+            "disconnect" => Ok(OnError::Disconnect),
+            "skip" => Ok(OnError::Skip),
+            "resync" => Ok(OnError::Resync),
+            _ => Err(format!("unknown error policy '{}'", s)),
         }
-        match protobuf::parse_from_bytes::<Payload>(&buf) {
-            Ok(pyld) => {
-                println!("PAYLOAD: {:?}", pyld);
+    }
+}
+
+/// Periodically publish the total number of points received, for emitters
+/// searching for a saturation point
+fn publish(path: String) {
+    let tmp = format!("{}.tmp", path);
+    loop {
+        // the units native_emitter paces and counts by
+        let entries =
+            POINTS_RECEIVED.load(Ordering::Relaxed) + LINES_RECEIVED.load(Ordering::Relaxed);
+        fs::write(&tmp, format!("{}\n", entries)).unwrap();
+        fs::rename(&tmp, &path).unwrap();
+        thread::sleep(time::Duration::from_millis(100));
+    }
+}
+
+/// Report what was received in the last second
+///
+/// Points, log lines and frame errors are kept as running totals, the count
+/// file and frame error messages reporting those, so the rates are their
+/// deltas.
+fn tick() {
+    let (mut points, mut lines, mut errors) = (0, 0, 0);
+    loop {
+        let second = time::Duration::from_millis(1000);
+        thread::sleep(second);
+        let payloads = PAYLOADS_RECEIVED.swap(0, Ordering::Relaxed);
+        let bytes = BYTES_RECEIVED.swap(0, Ordering::Relaxed);
+        let (last_points, last_lines, last_errors) = (points, lines, errors);
+        points = POINTS_RECEIVED.load(Ordering::Relaxed);
+        lines = LINES_RECEIVED.load(Ordering::Relaxed);
+        errors = FRAME_ERRORS.load(Ordering::Relaxed);
+        println!(
+            "PAYLOADS PER SECOND: {} | POINTS PER SECOND: {} | LOG LINES PER SECOND: {} | BYTES PER SECOND: {} | DECODE ERRORS PER SECOND: {}",
+            payloads,
+            points - last_points,
+            lines - last_lines,
+            bytes,
+            errors - last_errors
+        );
+        SEQUENCES.lock().unwrap().report();
+    }
+}
+
+/// Count a bad frame, printing one in every `dump_errors`
+fn frame_error(e: &FrameError, dump_errors: usize) {
+    let seen = FRAME_ERRORS.fetch_add(1, Ordering::Relaxed);
+    if dump_errors > 0 && seen.is_multiple_of(dump_errors) {
+        println!("FRAME ERROR: {} | TOTAL FRAME ERRORS: {}", e, seen + 1);
+    }
+}
+
+fn handle_client(
+    mut stream: Stream,
+    max_frame: usize,
+    on_error: OnError,
+    dump: usize,
+    dump_errors: usize,
+) {
+    let mut decoder = Decoder::new(max_frame);
+    // while resyncing, a run of bad bytes counts as a single error
+    let mut resyncing = false;
+
+    loop {
+        match decoder.decode() {
+            Ok(Some(pyld)) => {
+                resyncing = false;
+                let seen = PAYLOADS_SEEN.fetch_add(1, Ordering::Relaxed);
+                PAYLOADS_RECEIVED.fetch_add(1, Ordering::Relaxed);
+                // sequence number points are accounted for apart from points
+                let mut points = 0;
+                for point in pyld.get_points() {
+                    match (sequence::parse_source(point.get_name()), point.get_samples().first()) {
+                        (Some(source), Some(seq)) => {
+                            SEQUENCES.lock().unwrap().record(source, *seq as u64)
+                        }
+                        _ => points += 1,
+                    }
+                }
+                POINTS_RECEIVED.fetch_add(points, Ordering::Relaxed);
+                LINES_RECEIVED.fetch_add(pyld.get_lines().len(), Ordering::Relaxed);
+                if dump > 0 && seen.is_multiple_of(dump) {
+                    println!("PAYLOAD: {:?}", pyld);
+                }
             }
+            Ok(None) => match decoder.read_from(&mut stream) {
+                Ok(0) => {
+                    if let Err(e) = decoder.finish() {
+                        if !resyncing {
+                            frame_error(&e, dump_errors);
+                        }
+                    }
+                    return;
+                }
+                Ok(read) => {
+                    BYTES_RECEIVED.fetch_add(read, Ordering::Relaxed);
+                }
+                Err(e) => {
+                    println!("READ ERROR: {}", e);
+                    return;
+                }
+            },
             Err(e) => {
-                println!("PAYLOAD ERROR: {:?}", e);
-                return;
+                if !resyncing {
+                    frame_error(&e, dump_errors);
+                }
+                match on_error {
+                    OnError::Disconnect => return,
+                    OnError::Skip => decoder.skip_frame(),
+                    OnError::Resync => {
+                        resyncing = true;
+                        decoder.skip_byte();
+                    }
+                }
             }
         }
     }
 }
 
-fn recv() {
-    let listener = TcpListener::bind("127.0.0.1:1972").unwrap();
-
-    for stream in listener.incoming() {
-        thread::spawn(|| handle_client(stream.unwrap()));
+fn recv(listener: Listener, max_frame: usize, on_error: OnError, dump: usize, dump_errors: usize) {
+    loop {
+        let stream = listener.accept();
+        thread::spawn(move || {
+            handle_client(stream.unwrap(), max_frame, on_error, dump, dump_errors)
+        });
     }
 }
 
 fn main() {
-    thread::spawn(recv).join().unwrap();
+    let matches = App::new("native_listener")
+        .about("receives cernan native payloads")
+        .arg(
+            Arg::with_name("host")
+                .long("host")
+                .takes_value(true)
+                .default_value("127.0.0.1")
+                .help("Sets the address to listen on, an IPv4 or IPv6 address or unix://path")
+                .validator(|s| match s.parse::<Endpoint>()? {
+                    Endpoint::UnixDgram(_) => Err("native needs a unix:// socket".into()),
+                    _ => Ok(()),
+                }),
+        )
+        .arg(
+            Arg::with_name("port")
+                .long("port")
+                .takes_value(true)
+                .default_value("1972")
+                .help("Sets the port to listen on"),
+        )
+        .arg(
+            Arg::with_name("max_frame")
+                .long("max_frame")
+                .takes_value(true)
+                .help("Sets the largest frame accepted, in bytes [default: 64MiB]")
+                .validator(|s| s.parse::<usize>().map(|_| ()).map_err(|e| e.to_string())),
+        )
+        .arg(
+            Arg::with_name("on_error")
+                .long("on_error")
+                .takes_value(true)
+                .default_value("disconnect")
+                .validator(|s| s.parse::<OnError>().map(|_| ()))
+                .help("Sets what to do after a bad frame: close the connection, drop the frame by its size prefix, or drop bytes until frames decode again"),
+        )
+        .arg(
+            Arg::with_name("dump")
+                .long("dump")
+                .takes_value(true)
+                .default_value("0")
+                .help("Prints one payload in every N received, 0 printing none")
+                .validator(|s| s.parse::<usize>().map(|_| ()).map_err(|e| e.to_string())),
+        )
+        .arg(
+            Arg::with_name("dump_errors")
+                .long("dump_errors")
+                .takes_value(true)
+                .default_value("0")
+                .help("Prints one frame error in every N, 0 printing none")
+                .validator(|s| s.parse::<usize>().map(|_| ()).map_err(|e| e.to_string())),
+        )
+        .arg(
+            Arg::with_name("duration")
+                .long("duration")
+                .takes_value(true)
+                .validator(|s| parse_duration(&s).map(|_| ()))
+                .help("Stops listening after this long, like 30s or 5m, and prints totals"),
+        )
+        .arg(
+            Arg::with_name("count_file")
+                .long("count_file")
+                .takes_value(true)
+                .help("File to keep updated with the total number of points and log lines received")
+                .required(false),
+        )
+        .get_matches();
+
+    if let Some(path) = matches.value_of("count_file") {
+        let path = path.to_string();
+        thread::spawn(move || publish(path));
+    }
+
+    let endpoint = matches.value_of("host").unwrap().parse::<Endpoint>().unwrap();
+    let port = matches.value_of("port").unwrap().parse::<u16>().unwrap();
+    let max_frame = matches
+        .value_of("max_frame")
+        .map_or(DEFAULT_MAX_FRAME, |s| s.parse::<usize>().unwrap());
+    let on_error = matches.value_of("on_error").unwrap().parse::<OnError>().unwrap();
+    let dump = matches.value_of("dump").unwrap().parse::<usize>().unwrap();
+    let dump_errors = matches
+        .value_of("dump_errors")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap();
+
+    thread::spawn(tick);
+    let listener = Listener::bind(&endpoint, port).unwrap();
+    thread::spawn(move || recv(listener, max_frame, on_error, dump, dump_errors));
+
+    let duration = match matches.value_of("duration") {
+        Some(duration) => parse_duration(duration).unwrap(),
+        None => loop {
+            thread::park();
+        },
+    };
+    thread::sleep(time::Duration::from_millis((duration * 1000.0) as u64));
+
+    println!("LISTEN COMPLETE");
+    println!(
+        "TOTAL POINTS: {} | TOTAL LOG LINES: {} | TOTAL FRAME ERRORS: {}",
+        POINTS_RECEIVED.load(Ordering::Relaxed),
+        LINES_RECEIVED.load(Ordering::Relaxed),
+        FRAME_ERRORS.load(Ordering::Relaxed)
+    );
+    let sequences = SEQUENCES.lock().unwrap();
+    if !sequences.is_empty() {
+        println!("SEQUENCES");
+        sequences.report();
+    }
 }
@@ -0,0 +1,322 @@
+extern crate clap;
+#[macro_use]
+extern crate lazy_static;
+extern crate llrv;
+
+use clap::{App, Arg};
+use llrv::generator::statsd::{MetricType, Tally};
+use llrv::profile::parse_duration;
+use llrv::protocols::statsd::{parse_line, Line};
+use llrv::sequence::{self, Sequences};
+use llrv::transport::{DatagramListener, Endpoint, Listener, Stream};
+use std::io::Read;
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time;
+
+lazy_static! {
+    static ref LINES_RECEIVED: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+    static ref PACKETS_RECEIVED: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+    static ref PARSE_ERRORS: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+    static ref EVENTS_RECEIVED: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+    static ref SERVICE_CHECKS_RECEIVED: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+    static ref TALLY: Mutex<Tally> = Mutex::new(Tally::default());
+    static ref SEQUENCES: Mutex<Sequences> = Mutex::new(Sequences::default());
+}
+
+/// Report what was received in the last second
+///
+/// Every counter is a running total, for the summary printed when listening
+/// completes, so the rates are their deltas.
+fn tick() {
+    let (mut lines, mut packets, mut errors) = (0, 0, 0);
+    let (mut events, mut checks) = (0, 0);
+    let mut tally = Tally::default();
+    loop {
+        let second = time::Duration::from_millis(1000);
+        thread::sleep(second);
+        let last = (lines, packets, errors, events, checks, tally);
+        lines = LINES_RECEIVED.load(Ordering::Relaxed);
+        packets = PACKETS_RECEIVED.load(Ordering::Relaxed);
+        errors = PARSE_ERRORS.load(Ordering::Relaxed);
+        events = EVENTS_RECEIVED.load(Ordering::Relaxed);
+        checks = SERVICE_CHECKS_RECEIVED.load(Ordering::Relaxed);
+        tally = TALLY.lock().unwrap().clone();
+        println!(
+            "LINES PER SECOND: {} | TOTAL PACKETS PER SECOND: {} | PARSE ERRORS PER SECOND: {}",
+            lines - last.0,
+            packets - last.1,
+            errors - last.2
+        );
+        let types: Vec<String> = MetricType::ALL
+            .iter()
+            .map(|t| format!("{}: {}", t, tally.lines(*t) - last.5.lines(*t)))
+            .collect();
+        println!(
+            "  {} | EVENTS: {} | SERVICE CHECKS: {}",
+            types.join(" | "),
+            events - last.3,
+            checks - last.4
+        );
+        SEQUENCES.lock().unwrap().report();
+    }
+}
+
+/// Parse and count the newline separated lines in `bytes`
+///
+/// Sequence number lines are accounted for separately, not as lines.
+fn receive(bytes: &[u8], dump_errors: usize) {
+    let mut tally = Tally::default();
+    let (mut lines, mut events, mut checks) = (0, 0, 0);
+    let mut seqs = Vec::new();
+    for line in bytes.split(|b| *b == b'\n').filter(|l| !l.is_empty()) {
+        let parsed = str::from_utf8(line)
+            .map_err(|e| format!("{} in '{}'", e, String::from_utf8_lossy(line)))
+            .and_then(parse_line);
+        if let Ok(Line::Metric(ref metric)) = parsed {
+            if let Some(source) = sequence::parse_source(metric.name) {
+                if let Ok(seq) = metric.values[0].parse::<u64>() {
+                    seqs.push((source.to_string(), seq));
+                    continue;
+                }
+            }
+        }
+        lines += 1;
+        match parsed {
+            Ok(Line::Metric(metric)) => {
+                tally.record(metric.metric_type, 1, metric.total(), metric.rate())
+            }
+            Ok(Line::Event(_)) => events += 1,
+            Ok(Line::ServiceCheck(_)) => checks += 1,
+            Err(e) => {
+                let seen = PARSE_ERRORS.fetch_add(1, Ordering::Relaxed);
+                if dump_errors > 0 && seen.is_multiple_of(dump_errors) {
+                    println!("PARSE ERROR: {}", e);
+                }
+            }
+        }
+    }
+    LINES_RECEIVED.fetch_add(lines, Ordering::Relaxed);
+    EVENTS_RECEIVED.fetch_add(events, Ordering::Relaxed);
+    SERVICE_CHECKS_RECEIVED.fetch_add(checks, Ordering::Relaxed);
+    TALLY.lock().unwrap().merge(&tally);
+    if !seqs.is_empty() {
+        let mut sequences = SEQUENCES.lock().unwrap();
+        for (source, seq) in seqs {
+            sequences.record(&source, seq);
+        }
+    }
+}
+
+fn recv_datagrams(socket: DatagramListener, dump_errors: usize) {
+    // the largest datagram UDP can carry
+    let mut buf = vec![0; 65_536];
+    loop {
+        match socket.recv(&mut buf) {
+            Ok(read) => {
+                PACKETS_RECEIVED.fetch_add(1, Ordering::Relaxed);
+                receive(&buf[..read], dump_errors);
+            }
+            Err(e) => println!("READ ERROR: {}", e),
+        }
+    }
+}
+
+fn handle_client(mut stream: Stream, dump_errors: usize) {
+    let mut chunk = vec![0; 64 * 1024];
+    // a line split across reads, waiting for its end
+    let mut partial: Vec<u8> = Vec::new();
+    loop {
+        let read = match stream.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) => {
+                println!("READ ERROR: {}", e);
+                break;
+            }
+        };
+        PACKETS_RECEIVED.fetch_add(1, Ordering::Relaxed);
+        partial.extend_from_slice(&chunk[..read]);
+        if let Some(end) = partial.iter().rposition(|b| *b == b'\n') {
+            receive(&partial[..end], dump_errors);
+            partial.drain(..end + 1);
+        }
+    }
+    // a final line without a newline still counts
+    receive(&partial, dump_errors);
+}
+
+fn recv_streams(listener: Listener, dump_errors: usize) {
+    loop {
+        let stream = listener.accept();
+        thread::spawn(move || handle_client(stream.unwrap(), dump_errors));
+    }
+}
+
+fn main() {
+    let matches = App::new("statsd_listener")
+        .about("receives statsd lines and counts them")
+        .arg(
+            Arg::with_name("host")
+                .long("host")
+                .takes_value(true)
+                .default_value("127.0.0.1")
+                .validator(|s| s.parse::<Endpoint>().map(|_| ()))
+                .help("Sets the address to listen on, an IPv4 or IPv6 address, unix-dgram://path or, with tcp, unix://path"),
+        )
+        .arg(
+            Arg::with_name("transport")
+                .long("transport")
+                .takes_value(true)
+                .possible_values(&["udp", "tcp"])
+                .default_value("udp")
+                .help("Receive packets as datagrams or streams of newline separated lines"),
+        )
+        .arg(
+            Arg::with_name("udp_port")
+                .long("udp_port")
+                .takes_value(true)
+                .default_value("8125")
+                .help("Sets the UDP port to listen on"),
+        )
+        .arg(
+            Arg::with_name("tcp_port")
+                .long("tcp_port")
+                .takes_value(true)
+                .default_value("8125")
+                .help("Sets the TCP port to listen on"),
+        )
+        .arg(
+            Arg::with_name("duration")
+                .long("duration")
+                .takes_value(true)
+                .validator(|s| parse_duration(&s).map(|_| ()))
+                .help("Stops listening after this long, like 30s or 5m, and prints totals"),
+        )
+        .arg(
+            Arg::with_name("dump_errors")
+                .long("dump_errors")
+                .takes_value(true)
+                .default_value("0")
+                .help("Prints one parse error in every N, 0 printing none")
+                .validator(|s| s.parse::<usize>().map(|_| ()).map_err(|e| e.to_string())),
+        )
+        .get_matches();
+
+    let endpoint = matches.value_of("host").unwrap().parse::<Endpoint>().unwrap();
+    let tcp = matches.value_of("transport") == Some("tcp");
+    let port = matches
+        .value_of(if tcp { "tcp_port" } else { "udp_port" })
+        .unwrap()
+        .parse::<u16>()
+        .unwrap();
+    let dump_errors = matches
+        .value_of("dump_errors")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap();
+
+    let mismatch = match endpoint {
+        Endpoint::Unix(_) if !tcp => Some("unix:// is a stream socket, use --transport tcp"),
+        Endpoint::UnixDgram(_) if tcp => {
+            Some("unix-dgram:// is a datagram socket, use --transport udp")
+        }
+        _ => None,
+    };
+    if let Some(mismatch) = mismatch {
+        clap::Error::with_description(mismatch, clap::ErrorKind::ArgumentConflict).exit();
+    }
+    if tcp {
+        let listener = Listener::bind(&endpoint, port).unwrap();
+        thread::spawn(move || recv_streams(listener, dump_errors));
+    } else {
+        let socket = DatagramListener::bind(&endpoint, port).unwrap();
+        thread::spawn(move || recv_datagrams(socket, dump_errors));
+    }
+    thread::spawn(tick);
+
+    let duration = match matches.value_of("duration") {
+        Some(duration) => parse_duration(duration).unwrap(),
+        None => loop {
+            thread::park();
+        },
+    };
+    thread::sleep(time::Duration::from_millis((duration * 1000.0) as u64));
+
+    println!("LISTEN COMPLETE");
+    println!(
+        "TOTAL LINES: {} | TOTAL PACKETS: {} | TOTAL PARSE ERRORS: {}",
+        LINES_RECEIVED.load(Ordering::Relaxed),
+        PACKETS_RECEIVED.load(Ordering::Relaxed),
+        PARSE_ERRORS.load(Ordering::Relaxed)
+    );
+    println!(
+        "TOTAL EVENTS: {} | TOTAL SERVICE CHECKS: {}",
+        EVENTS_RECEIVED.load(Ordering::Relaxed),
+        SERVICE_CHECKS_RECEIVED.load(Ordering::Relaxed)
+    );
+    let tally = TALLY.lock().unwrap();
+    println!("EFFECTIVE COUNTS");
+    for metric_type in MetricType::ALL.iter() {
+        println!(
+            "{:<2}{:<15}LINES: {} | EFFECTIVE: {:.1}",
+            "",
+            format!("{}:", metric_type),
+            tally.lines(*metric_type),
+            tally.effective(*metric_type)
+        );
+    }
+    let sequences = SEQUENCES.lock().unwrap();
+    if !sequences.is_empty() {
+        println!("SEQUENCES");
+        sequences.report();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+    use std::{env, fs, process};
+
+    fn totals() -> (usize, usize, usize, usize) {
+        (
+            LINES_RECEIVED.load(Ordering::Relaxed),
+            PARSE_ERRORS.load(Ordering::Relaxed),
+            EVENTS_RECEIVED.load(Ordering::Relaxed),
+            SERVICE_CHECKS_RECEIVED.load(Ordering::Relaxed),
+        )
+    }
+
+    // one test, the counters being global
+    #[test]
+    fn counts_lines_by_kind() {
+        let name = sequence::metric_name("src_0");
+        let packet = format!(
+            "a:1|c|@0.5\n{}:0|g\nb:NaN|g\n_e{{1,1}}:t|x\n_sc|db|0\n\nc:2|ms\n",
+            name
+        );
+        receive(packet.as_bytes(), 0);
+        assert_eq!(totals(), (5, 1, 1, 1));
+        assert_eq!(TALLY.lock().unwrap().effective(MetricType::Counter), 2.0);
+        assert_eq!(TALLY.lock().unwrap().lines(MetricType::Timer), 1);
+        assert!(!SEQUENCES.lock().unwrap().is_empty());
+
+        // lines split across reads of a stream, the last without a newline
+        let path = env::temp_dir().join(format!("statsd_listener_{}", process::id()));
+        let endpoint = Endpoint::Unix(path.clone());
+        let listener = Listener::bind(&endpoint, 0).unwrap();
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"d:1|c\ne:").unwrap();
+        client.write_all(b"2|c\nf:3|").unwrap();
+        client.write_all(b"c").unwrap();
+        drop(client);
+        handle_client(listener.accept().unwrap(), 0);
+        let _ = fs::remove_file(&path);
+        assert_eq!(totals(), (8, 1, 1, 1));
+        assert_eq!(TALLY.lock().unwrap().effective(MetricType::Counter), 8.0);
+    }
+}
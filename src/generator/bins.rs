@@ -0,0 +1,181 @@
+//! Bin bounds for `BIN` telemetry
+//!
+//! A set of bounds b0 < b1 < ... < bn splits the line into the bins
+//! (-inf, b0], (b0, b1], ..., (bn, +inf). Samples for a metric are drawn so
+//! that each of these bins receives the same share of them, which lets the
+//! counts a server reports be checked against a known proportion.
+
+use rand::Rng;
+use std::str::FromStr;
+
+/// A set of bin bounds
+///
+/// Written as one of
+///
+/// * `linear:START:WIDTH:COUNT`, COUNT bounds WIDTH apart from START
+/// * `exp:START:FACTOR:COUNT`, COUNT bounds each FACTOR times the last
+/// * `list:B0,B1,...`, the given bounds
+///
+/// followed by any of `+unsorted`, sending the bounds in reverse order, and
+/// `+duplicate`, sending every other bound twice. Faulty bounds are for
+/// negative testing; samples still follow the sorted, distinct bounds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bounds {
+    sent: Vec<f64>,
+    sorted: Vec<f64>,
+}
+
+impl Bounds {
+    /// The bounds as sent on the wire
+    pub fn bounds(&self) -> &[f64] {
+        &self.sent
+    }
+
+    /// Number of bins, including the two unbounded ones
+    pub fn bins(&self) -> usize {
+        self.sorted.len() + 1
+    }
+
+    /// Draw a sample from a bin picked uniformly
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> f64 {
+        let sorted = &self.sorted;
+        let last = sorted.len() - 1;
+        // the unbounded bins are as wide as their bounded neighbour
+        let edge = |i: usize, j: usize| (sorted[j] - sorted[i]).abs().max(1.0);
+        let u = rng.gen::<f64>();
+        match rng.gen_range(0, self.bins()) {
+            0 => sorted[0] - u * edge(0, last.min(1)),
+            bin if bin == sorted.len() => sorted[last] + (1.0 - u) * edge(last.saturating_sub(1), last),
+            bin => sorted[bin] - u * (sorted[bin] - sorted[bin - 1]),
+        }
+    }
+}
+
+impl FromStr for Bounds {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Bounds, String> {
+        let mut modifiers = s.split('+');
+        let spec = modifiers.next().unwrap();
+        let args: Vec<&str> = spec.split(':').collect();
+        let number = |arg: &str| {
+            arg.parse::<f64>()
+                .ok()
+                .filter(|n| n.is_finite())
+                .ok_or_else(|| format!("'{}' in '{}' is not a number", arg, s))
+        };
+        let count = |arg: &str| {
+            arg.parse::<usize>()
+                .ok()
+                .filter(|n| *n > 0)
+                .ok_or_else(|| format!("'{}' in '{}' is not a positive count", arg, s))
+        };
+        let mut sorted: Vec<f64> = match (args[0], args.len()) {
+            ("linear", 4) => {
+                let (start, width) = (number(args[1])?, number(args[2])?);
+                (0..count(args[3])?)
+                    .map(|i| start + width * i as f64)
+                    .collect()
+            }
+            ("exp", 4) => {
+                let (start, factor) = (number(args[1])?, number(args[2])?);
+                (0..count(args[3])?)
+                    .map(|i| start * factor.powi(i as i32))
+                    .collect()
+            }
+            ("list", 2) => args[1]
+                .split(',')
+                .map(|b| number(b.trim()))
+                .collect::<Result<_, _>>()?,
+            _ => return Err(format!("unknown bin bounds '{}'", s)),
+        };
+        // checked before sorting, NaN having no order
+        if sorted.is_empty() || sorted.iter().any(|b| !b.is_finite()) {
+            return Err(format!("bin bounds '{}' are empty or not finite", s));
+        }
+        let mut sent = sorted.clone();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        sorted.dedup();
+        for modifier in modifiers {
+            match modifier {
+                "unsorted" => sent.reverse(),
+                "duplicate" => {
+                    sent = sent
+                        .iter()
+                        .enumerate()
+                        .flat_map(|(i, b)| vec![*b; 1 + (i % 2 == 0) as usize])
+                        .collect()
+                }
+                _ => return Err(format!("unknown bin bounds modifier '+{}'", modifier)),
+            }
+        }
+        Ok(Bounds { sent, sorted })
+    }
+}
+
+/// Parse bound sets separated by `;`
+pub fn parse_bound_sets(s: &str) -> Result<Vec<Bounds>, String> {
+    s.split(';')
+        .map(|set| set.trim())
+        .filter(|set| !set.is_empty())
+        .map(|set| set.parse::<Bounds>())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use generator::seeded_rng;
+
+    #[test]
+    fn bounds_parse() {
+        let bounds = "linear:0:10:3".parse::<Bounds>().unwrap();
+        assert_eq!(bounds.bounds(), &[0.0, 10.0, 20.0]);
+        assert_eq!(bounds.bins(), 4);
+        let bounds = "exp:1:2:4+unsorted".parse::<Bounds>().unwrap();
+        assert_eq!(bounds.bounds(), &[8.0, 4.0, 2.0, 1.0]);
+        let bounds = "list:3,1,2+duplicate".parse::<Bounds>().unwrap();
+        assert_eq!(bounds.bounds(), &[3.0, 3.0, 1.0, 2.0, 2.0]);
+        assert_eq!(bounds.bins(), 4);
+        assert_eq!(parse_bound_sets("linear:0:1:2; list:5").unwrap().len(), 2);
+
+        // 1e300 squared overflows, and 0 times infinity is NaN
+        assert!("exp:0:1e300:3".parse::<Bounds>().is_err());
+        assert!("exp:1:1e300:3".parse::<Bounds>().is_err());
+        assert!("linear:0:1:0".parse::<Bounds>().is_err());
+        assert!("list:1,x".parse::<Bounds>().is_err());
+        assert!("list:1+shuffled".parse::<Bounds>().is_err());
+        assert!("log:1:2:3".parse::<Bounds>().is_err());
+    }
+
+    #[test]
+    fn samples_fill_bins_evenly() {
+        for spec in &[
+            "list:-5,1,2,10,1000",
+            "list:7",
+            "linear:0:0.5:3+unsorted+duplicate",
+        ] {
+            let bounds = spec.parse::<Bounds>().unwrap();
+            let mut rng = seeded_rng(9);
+            let mut counts = vec![0; bounds.bins()];
+            let draws = 20_000 * bounds.bins();
+            for _ in 0..draws {
+                let sample = bounds.sample(&mut rng);
+                // bins are closed above: (b[i-1], b[i]]
+                let bin = bounds.sorted.iter().filter(|b| sample > **b).count();
+                counts[bin] += 1;
+            }
+            for count in counts {
+                let share = count as f64 / draws as f64;
+                let expected = 1.0 / bounds.bins() as f64;
+                assert!(
+                    (share - expected).abs() < 0.01,
+                    "{}: {} vs {}",
+                    spec,
+                    share,
+                    expected
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,127 @@
+//! Cardinality churn
+//!
+//! Short-lived series, per-pod or per-request-id, keep appearing in real
+//! traffic while old ones fall silent. Churn retires pool entries at a set
+//! pace and replaces them with fresh names, so a server's series count keeps
+//! growing for as long as the run lasts.
+
+use generator::names::Namer;
+use rand::Rng;
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::time::Instant;
+
+/// What churn is paced against
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Per {
+    Second,
+    Units(f64),
+}
+
+/// The pace at which pool entries are replaced
+///
+/// Written `N/s` for N entries per second, or `N/M` for N entries every M
+/// lines or points produced. Only the latter is reproducible from a seed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Churn {
+    count: f64,
+    per: Per,
+}
+
+impl Churn {
+    /// Spread this churn over generators producing `factor` of the traffic
+    ///
+    /// Churn paced by units already scales with each generator's output.
+    pub fn scaled(&self, factor: f64) -> Churn {
+        match self.per {
+            Per::Second => Churn {
+                count: self.count * factor,
+                per: Per::Second,
+            },
+            Per::Units(_) => *self,
+        }
+    }
+}
+
+impl FromStr for Churn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Churn, String> {
+        let err = || format!("'{}' is not a churn like '50/s' or '1/1000'", s);
+        let idx = s.find('/').ok_or_else(err)?;
+        let count = s[..idx].trim().parse::<f64>().map_err(|_| err())?;
+        let per = match s[idx + 1..].trim() {
+            "s" => Per::Second,
+            units => Per::Units(
+                units
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|u| *u > 0.0)
+                    .ok_or_else(err)?,
+            ),
+        };
+        if count < 0.0 || !count.is_finite() {
+            return Err(err());
+        }
+        Ok(Churn { count, per })
+    }
+}
+
+/// Tracks how many pool entries are due for replacement
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    churn: Churn,
+    last: Instant,
+    debt: f64,
+    names: HashSet<String>,
+    retired: usize,
+}
+
+impl Schedule {
+    /// Start churning a pool whose current names are `names`
+    pub fn new<'a, I: Iterator<Item = &'a String>>(churn: Churn, names: I) -> Schedule {
+        Schedule {
+            churn,
+            last: Instant::now(),
+            debt: 0.0,
+            names: names.cloned().collect(),
+            retired: 0,
+        }
+    }
+
+    /// Record that `units` lines or points were produced, returning how many
+    /// entries should be replaced now
+    pub fn due(&mut self, units: usize) -> usize {
+        self.debt += match self.churn.per {
+            Per::Second => {
+                let now = Instant::now();
+                let elapsed = now.duration_since(self.last).as_secs_f64();
+                self.last = now;
+                elapsed * self.churn.count
+            }
+            Per::Units(per) => units as f64 * self.churn.count / per,
+        };
+        let due = self.debt.floor();
+        self.debt -= due;
+        due as usize
+    }
+
+    /// Retire `old` in favour of a name not seen in the pool, or None if
+    /// the namer failed to come up with one
+    pub fn replace<R: Rng>(&mut self, rng: &mut R, namer: &mut Namer, old: &str) -> Option<String> {
+        for _ in 0..10 {
+            let name = namer.name(rng);
+            if self.names.insert(name.clone()) {
+                self.names.remove(old);
+                self.retired += 1;
+                return Some(name);
+            }
+        }
+        None
+    }
+
+    /// Number of entries retired so far
+    pub fn retired(&self) -> usize {
+        self.retired
+    }
+}
@@ -0,0 +1,192 @@
+//! The DogStatsD dialect of statsd
+//!
+//! DogStatsD extends a statsd metric line with `|#key:value,...` tags, a
+//! `|c:` container ID and a `|T` unix timestamp, and adds two line kinds of
+//! its own: events (`_e{..}`) and service checks (`_sc`). Every metric name
+//! gets its own set of distinct tag combinations so that the number of series
+//! behind a name can be dialed up to stress series explosion in aggregators.
+
+use generator::{now_ms, word};
+use rand::Rng;
+
+/// Settings of the DogStatsD dialect
+#[derive(Debug, Clone, PartialEq)]
+pub struct DogStatsd {
+    /// Number of tags on each line
+    pub tags: usize,
+    /// Number of distinct tag combinations for each metric name
+    pub tag_cardinality: usize,
+    /// Percentage of packets that carry an event instead of metrics
+    pub events: u32,
+    /// Percentage of packets that carry a service check instead of metrics
+    pub service_checks: u32,
+    /// Number of distinct container IDs lines are attributed to, 0 for none
+    pub container_ids: usize,
+    /// Whether lines carry a `|T` timestamp
+    pub timestamps: bool,
+}
+
+impl Default for DogStatsd {
+    fn default() -> DogStatsd {
+        DogStatsd {
+            tags: 0,
+            tag_cardinality: 1,
+            events: 0,
+            service_checks: 0,
+            container_ids: 0,
+            timestamps: false,
+        }
+    }
+}
+
+/// The kind of line a DogStatsD packet carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Metric,
+    Event,
+    ServiceCheck,
+}
+
+/// The pre-generated tags and container IDs of a metric pool
+#[derive(Clone)]
+pub struct Extensions {
+    config: DogStatsd,
+    tag_sets: Vec<Vec<String>>,
+    containers: Vec<String>,
+}
+
+const PRIORITIES: [&str; 2] = ["normal", "low"];
+const ALERT_TYPES: [&str; 4] = ["error", "warning", "info", "success"];
+
+impl Extensions {
+    /// Generate tags for each of `pool_size` metric names
+    pub fn new<R: Rng>(rng: &mut R, config: &DogStatsd, pool_size: usize) -> Extensions {
+        let mut tag_sets = Vec::with_capacity(pool_size);
+        for _ in 0..pool_size {
+            let keys: Vec<String> = (0..config.tags).map(|_| word(rng, 3, 10)).collect();
+            let sets = (0..config.tag_cardinality.max(1))
+                .map(|_| {
+                    let tags: Vec<String> = keys
+                        .iter()
+                        .map(|key| format!("{}:{}", key, word(rng, 1, 12)))
+                        .collect();
+                    tags.join(",")
+                })
+                .collect();
+            tag_sets.push(sets);
+        }
+        let containers = (0..config.container_ids)
+            .map(|_| {
+                let id: String = (0..64)
+                    .map(|_| *rng.choose(b"0123456789abcdef").unwrap() as char)
+                    .collect();
+                id
+            })
+            .collect();
+        Extensions {
+            config: config.clone(),
+            tag_sets,
+            containers,
+        }
+    }
+
+    /// Decide what the next packet carries
+    pub fn kind<R: Rng>(&self, rng: &mut R) -> Kind {
+        let roll = rng.gen_range(0, 100);
+        if roll < self.config.events {
+            Kind::Event
+        } else if roll < self.config.events + self.config.service_checks {
+            Kind::ServiceCheck
+        } else {
+            Kind::Metric
+        }
+    }
+
+    /// Append the DogStatsD fields following a metric line's type and
+    /// sample rate for the metric at `entry` in the pool
+    pub fn metric_fields<R: Rng>(&self, rng: &mut R, entry: usize, line: &mut String) {
+        self.push_tags(rng, entry, line);
+        if let Some(container) = rng.choose(&self.containers) {
+            line.push_str("|c:");
+            line.push_str(container);
+        }
+        if self.config.timestamps {
+            line.push_str(&format!("|T{}", now()));
+        }
+    }
+
+    /// Append an event about the metric at `entry` in the pool
+    pub fn event<R: Rng>(&self, rng: &mut R, entry: usize, line: &mut String) {
+        let title = sentence(rng, 1, 6);
+        let text = if rng.gen_weighted_bool(4) {
+            // multi-line text is sent with escaped newlines
+            (0..rng.gen_range(2, 6))
+                .map(|_| sentence(rng, 2, 12))
+                .collect::<Vec<_>>()
+                .join("\\n")
+        } else {
+            sentence(rng, 2, 30)
+        };
+        line.push_str(&format!(
+            "_e{{{},{}}}:{}|{}",
+            title.len(),
+            text.len(),
+            title,
+            text
+        ));
+        if self.config.timestamps {
+            line.push_str(&format!("|d:{}", now()));
+        }
+        if rng.gen() {
+            line.push_str("|h:");
+            line.push_str(&word(rng, 4, 16));
+        }
+        if rng.gen() {
+            line.push_str("|p:");
+            line.push_str(rng.choose(&PRIORITIES).unwrap());
+        }
+        if rng.gen() {
+            line.push_str("|t:");
+            line.push_str(rng.choose(&ALERT_TYPES).unwrap());
+        }
+        self.push_tags(rng, entry, line);
+    }
+
+    /// Append a service check named after the metric at `entry` in the pool
+    pub fn service_check<R: Rng>(&self, rng: &mut R, entry: usize, name: &str, line: &mut String) {
+        line.push_str(&format!("_sc|{}|{}", name, rng.gen_range(0, 4)));
+        if self.config.timestamps {
+            line.push_str(&format!("|d:{}", now()));
+        }
+        if rng.gen() {
+            line.push_str("|h:");
+            line.push_str(&word(rng, 4, 16));
+        }
+        self.push_tags(rng, entry, line);
+        if rng.gen() {
+            line.push_str("|m:");
+            line.push_str(&sentence(rng, 1, 12));
+        }
+    }
+
+    fn push_tags<R: Rng>(&self, rng: &mut R, entry: usize, line: &mut String) {
+        if self.config.tags > 0 {
+            line.push_str("|#");
+            line.push_str(rng.choose(&self.tag_sets[entry]).unwrap());
+        }
+    }
+}
+
+/// Seconds since the Unix epoch
+fn now() -> i64 {
+    now_ms() / 1_000
+}
+
+/// Space separated words, between `min` and `max` of them
+fn sentence<R: Rng>(rng: &mut R, min: usize, max: usize) -> String {
+    let len = rng.gen_range(min, max + 1);
+    (0..len)
+        .map(|_| word(rng, 1, 10))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
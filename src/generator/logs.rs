@@ -0,0 +1,354 @@
+//! Generation of log lines
+//!
+//! Log lines are written to a fixed set of paths and follow one of a few
+//! templates modelled on what cernan ingests in practice: web server access
+//! logs, structured JSON logs and multi-line stack traces. The length of
+//! each line's free text, the request path, message or exception message
+//! depending on the template, follows a configurable distribution.
+
+use generator::metadata::{Metadata, MetadataConfig};
+use generator::values::{Distribution, Sampler};
+use generator::{now_ms, word};
+use protocols::native::LogLine;
+use rand::Rng;
+use std::str::FromStr;
+
+/// The shape of generated log lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Template {
+    /// Apache combined access log lines
+    Access,
+    /// One JSON object per line
+    Json,
+    /// Java style multi-line stack traces
+    Stacktrace,
+    /// Free text alone
+    Plain,
+    /// Access, JSON and stack traces in equal measure
+    Mixed,
+}
+
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Template, String> {
+        match s {
+            "access" => Ok(Template::Access),
+            "json" => Ok(Template::Json),
+            "stacktrace" => Ok(Template::Stacktrace),
+            "plain" => Ok(Template::Plain),
+            "mixed" => Ok(Template::Mixed),
+            _ => Err(format!("unknown log template '{}'", s)),
+        }
+    }
+}
+
+/// Settings shaping generated log lines
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogConfig {
+    /// Number of distinct paths lines are written to
+    pub paths: usize,
+    /// The shape of lines
+    pub template: Template,
+    /// The distribution of the length of each line's free text
+    pub length: Distribution,
+    /// What metadata lines carry, fixed ones being fixed per path
+    pub metadata: MetadataConfig,
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig {
+            paths: 1,
+            template: Template::Plain,
+            length: Distribution::Int(20, 200),
+            metadata: MetadataConfig::default(),
+        }
+    }
+}
+
+/// Produces log lines following a `LogConfig`
+#[derive(Debug, Clone)]
+pub struct Logs {
+    template: Template,
+    paths: Vec<String>,
+    length: Sampler,
+    metadata: Metadata,
+}
+
+const METHODS: [&str; 5] = ["GET", "GET", "GET", "POST", "PUT"];
+const STATUSES: [u32; 8] = [200, 200, 200, 200, 201, 304, 404, 500];
+const LEVELS: [&str; 5] = ["debug", "info", "info", "warn", "error"];
+const AGENTS: [&str; 3] = [
+    "Mozilla/5.0 (X11; Linux x86_64; rv:57.0) Gecko/20100101 Firefox/57.0",
+    "curl/7.55.1",
+    "Go-http-client/1.1",
+];
+const EXCEPTIONS: [&str; 4] = [
+    "java.lang.IllegalStateException",
+    "java.lang.NullPointerException",
+    "java.io.IOException",
+    "java.util.concurrent.TimeoutException",
+];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+// a maximum on free text length, to keep wild distributions in check
+const MAX_LENGTH: usize = 1 << 20;
+
+impl Logs {
+    /// Draw the paths lines are written to
+    pub fn new<R: Rng>(rng: &mut R, config: &LogConfig) -> Logs {
+        let paths: Vec<String> = (0..config.paths.max(1))
+            .map(|i| format!("/var/log/{}/{}{}.log", word(rng, 3, 8), word(rng, 3, 8), i))
+            .collect();
+        let metadata = Metadata::new(rng, &config.metadata, paths.len());
+        Logs {
+            template: config.template,
+            length: Sampler::new(config.length, 0, 1),
+            metadata,
+            paths,
+        }
+    }
+
+    /// Produce the next log line, stamped at `timestamp_ms` if given
+    pub fn next_line<R: Rng>(&mut self, rng: &mut R, timestamp_ms: Option<i64>) -> LogLine {
+        let entry = rng.gen_range(0, self.paths.len());
+        let length = self.length.sample(rng, 0);
+        let length = if length.is_finite() && length > 0.0 {
+            (length as usize).min(MAX_LENGTH)
+        } else {
+            0
+        };
+        let template = match self.template {
+            Template::Mixed => *rng
+                .choose(&[Template::Access, Template::Json, Template::Stacktrace])
+                .unwrap(),
+            template => template,
+        };
+        let now = timestamp_ms.unwrap_or_else(now_ms);
+        let value = match template {
+            Template::Access => access(rng, length, now),
+            Template::Json => json(rng, length, now),
+            Template::Stacktrace => stacktrace(rng, length),
+            Template::Plain | Template::Mixed => text(rng, length),
+        };
+
+        let mut line = LogLine::new();
+        line.set_path(self.paths[entry].clone());
+        line.set_value(value);
+        if !self.metadata.is_empty() {
+            line.set_metadata(self.metadata.generate(rng, entry));
+        }
+        if let Some(timestamp_ms) = timestamp_ms {
+            line.set_timestamp_ms(timestamp_ms);
+        }
+        line
+    }
+}
+
+fn access<R: Rng>(rng: &mut R, length: usize, now: i64) -> String {
+    let (year, month, day, hour, minute, second) = civil(now);
+    let mut path = String::from("/");
+    while path.len() < length.max(1) {
+        path.push_str(&word(rng, 2, 10));
+        path.push('/');
+    }
+    path.truncate(length.max(1));
+    format!(
+        "{}.{}.{}.{} - - [{:02}/{}/{}:{:02}:{:02}:{:02} +0000] \"{} {} HTTP/1.1\" {} {} \"-\" \"{}\"",
+        rng.gen_range(1, 255),
+        rng.gen::<u8>(),
+        rng.gen::<u8>(),
+        rng.gen_range(1, 255),
+        day,
+        MONTHS[month - 1],
+        year,
+        hour,
+        minute,
+        second,
+        rng.choose(&METHODS).unwrap(),
+        path,
+        rng.choose(&STATUSES).unwrap(),
+        rng.gen_range(0, 100_000),
+        rng.choose(&AGENTS).unwrap()
+    )
+}
+
+fn json<R: Rng>(rng: &mut R, length: usize, now: i64) -> String {
+    let (year, month, day, hour, minute, second) = civil(now);
+    format!(
+        "{{\"ts\":\"{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z\",\"level\":\"{}\",\"service\":\"{}\",\
+         \"msg\":\"{}\",\"request_id\":\"{:016x}\",\"duration_ms\":{}}}",
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+        now.rem_euclid(1_000),
+        rng.choose(&LEVELS).unwrap(),
+        word(rng, 3, 10),
+        text(rng, length),
+        rng.gen::<u64>(),
+        rng.gen_range(0, 5_000)
+    )
+}
+
+fn stacktrace<R: Rng>(rng: &mut R, length: usize) -> String {
+    let mut trace = format!(
+        "Exception in thread \"{}\" {}: {}",
+        word(rng, 4, 10),
+        rng.choose(&EXCEPTIONS).unwrap(),
+        text(rng, length)
+    );
+    for _ in 0..rng.gen_range(5, 30) {
+        let class = format!("{}{}", capitalized(rng), capitalized(rng));
+        trace.push_str(&format!(
+            "\n\tat com.{}.{}.{}.{}({}.java:{})",
+            word(rng, 3, 8),
+            word(rng, 3, 8),
+            class,
+            word(rng, 3, 12),
+            class,
+            rng.gen_range(1, 2_000)
+        ));
+    }
+    trace
+}
+
+/// Space separated words making up exactly `length` bytes
+fn text<R: Rng>(rng: &mut R, length: usize) -> String {
+    let mut text = String::with_capacity(length + 10);
+    while text.len() < length {
+        if !text.is_empty() {
+            text.push(' ');
+        }
+        text.push_str(&word(rng, 1, 10));
+    }
+    text.truncate(length);
+    text
+}
+
+fn capitalized<R: Rng>(rng: &mut R) -> String {
+    let word = word(rng, 3, 8);
+    word[..1].to_uppercase() + &word[1..]
+}
+
+/// Split milliseconds since the epoch into a UTC year, month, day, hour,
+/// minute and second
+fn civil(ms: i64) -> (i64, usize, i64, i64, i64, i64) {
+    let secs = ms.div_euclid(1_000);
+    let (days, rem) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    // Howard Hinnant's civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (
+        year,
+        month as usize,
+        day,
+        rem / 3_600,
+        rem % 3_600 / 60,
+        rem % 60,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use generator::seeded_rng;
+    use std::collections::HashSet;
+
+    // 2018-01-16T10:45:07.250Z
+    const STAMP: i64 = 1_516_099_507_250;
+
+    fn lines(template: Template, length: Distribution, count: usize) -> Vec<LogLine> {
+        let config = LogConfig {
+            paths: 3,
+            template,
+            length,
+            metadata: MetadataConfig {
+                keys: 1,
+                key_cardinality: 1,
+                value_cardinality: 100,
+                fixed: true,
+            },
+        };
+        let mut rng = seeded_rng(13);
+        let mut logs = Logs::new(&mut rng, &config);
+        (0..count)
+            .map(|_| logs.next_line(&mut rng, Some(STAMP)))
+            .collect()
+    }
+
+    #[test]
+    fn civil_dates() {
+        assert_eq!(civil(0), (1970, 1, 1, 0, 0, 0));
+        assert_eq!(civil(STAMP), (2018, 1, 16, 10, 45, 7));
+        assert_eq!(civil(951_782_400_000), (2000, 2, 29, 0, 0, 0));
+        assert_eq!(civil(-1_000), (1969, 12, 31, 23, 59, 59));
+    }
+
+    #[test]
+    fn plain_text_has_the_drawn_length() {
+        for line in lines(Template::Plain, Distribution::Int(0, 50), 500) {
+            let value = line.get_value();
+            assert!(value.len() <= 50);
+            assert!(!value.starts_with(' '));
+            assert!(value.chars().all(|c| c == ' ' || c.is_ascii_lowercase()));
+            assert_eq!(line.get_timestamp_ms(), STAMP);
+        }
+        for line in lines(Template::Plain, Distribution::Int(37, 37), 10) {
+            assert_eq!(line.get_value().len(), 37);
+        }
+    }
+
+    #[test]
+    fn templates_shape_lines() {
+        let access = &lines(Template::Access, Distribution::Int(12, 12), 1)[0];
+        assert!(access.get_value().contains("[16/Jan/2018:10:45:07 +0000]"));
+        assert!(access.get_value().contains(" HTTP/1.1\" "));
+
+        let json = &lines(Template::Json, Distribution::Int(12, 12), 1)[0];
+        assert!(json
+            .get_value()
+            .starts_with("{\"ts\":\"2018-01-16T10:45:07.250Z\""));
+        assert!(json.get_value().ends_with('}'));
+
+        let trace = &lines(Template::Stacktrace, Distribution::Int(12, 12), 1)[0];
+        assert!(trace.get_value().starts_with("Exception in thread \""));
+        assert!(trace
+            .get_value()
+            .lines()
+            .skip(1)
+            .all(|l| l.starts_with("\tat com.")));
+
+        let mixed: HashSet<char> = lines(Template::Mixed, Distribution::Int(12, 12), 100)
+            .iter()
+            .map(|line| line.get_value().chars().next().unwrap())
+            .collect();
+        assert!(mixed.contains(&'{') && mixed.contains(&'E') && mixed.len() >= 3);
+    }
+
+    #[test]
+    fn paths_keep_their_metadata() {
+        let lines = lines(Template::Plain, Distribution::Int(5, 5), 300);
+        let paths: HashSet<&str> = lines.iter().map(|line| line.get_path()).collect();
+        assert_eq!(paths.len(), 3);
+        for path in paths {
+            let maps: HashSet<Vec<(&String, &String)>> = lines
+                .iter()
+                .filter(|line| line.get_path() == path)
+                .map(|line| line.get_metadata().iter().collect())
+                .collect();
+            assert_eq!(maps.len(), 1);
+        }
+    }
+}
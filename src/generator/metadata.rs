@@ -0,0 +1,187 @@
+//! Generation of metadata maps
+//!
+//! Metadata keys are drawn from a vocabulary of `key_cardinality` names and
+//! each key from a vocabulary of `value_cardinality` values, bounding the
+//! number of distinct series a server can see. Values are either fixed per
+//! pool entry, the way a host or region tag is, or drawn afresh for every
+//! point, the way a request id is.
+//!
+//! The protocol carries metadata in a `HashMap`, whose order changes from
+//! run to run, so the encoder writes maps in key order and a seed always
+//! produces the same bytes on the wire.
+
+use generator::word;
+use rand::Rng;
+use std::collections::HashMap;
+
+/// Settings shaping generated metadata
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetadataConfig {
+    /// Number of keys in each map
+    pub keys: usize,
+    /// Number of distinct key names
+    pub key_cardinality: usize,
+    /// Number of distinct values of each key
+    pub value_cardinality: usize,
+    /// Whether each pool entry keeps the same map, else a map is drawn for
+    /// every point
+    pub fixed: bool,
+}
+
+impl Default for MetadataConfig {
+    fn default() -> MetadataConfig {
+        MetadataConfig {
+            keys: 0,
+            key_cardinality: 1,
+            value_cardinality: 1,
+            fixed: false,
+        }
+    }
+}
+
+/// Produces metadata maps for the entries of a pool
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    config: MetadataConfig,
+    keys: Vec<String>,
+    values: Vec<Vec<String>>,
+    // the map of each pool entry, when fixed, drawn on first use
+    fixed: Vec<Option<HashMap<String, String>>>,
+}
+
+impl Metadata {
+    /// Draw the key and value vocabularies for a pool of `len` entries
+    pub fn new<R: Rng>(rng: &mut R, config: &MetadataConfig, len: usize) -> Metadata {
+        // without keys, leave the random stream untouched
+        let key_cardinality = if config.keys == 0 {
+            0
+        } else {
+            config.key_cardinality.max(config.keys)
+        };
+        let keys = (0..key_cardinality)
+            .map(|i| format!("{}{}", word(rng, 3, 10), i))
+            .collect();
+        let values = (0..key_cardinality)
+            .map(|_| {
+                (0..config.value_cardinality.max(1))
+                    .map(|_| word(rng, 3, 10))
+                    .collect()
+            })
+            .collect();
+        Metadata {
+            config: config.clone(),
+            keys,
+            values,
+            fixed: vec![None; if config.fixed { len } else { 0 }],
+        }
+    }
+
+    /// Whether maps have any keys at all
+    pub fn is_empty(&self) -> bool {
+        self.config.keys == 0
+    }
+
+    /// The map for the next point of pool entry `entry`
+    pub fn generate<R: Rng>(&mut self, rng: &mut R, entry: usize) -> HashMap<String, String> {
+        if !self.config.fixed {
+            return self.draw(rng);
+        }
+        if self.fixed[entry].is_none() {
+            self.fixed[entry] = Some(self.draw(rng));
+        }
+        self.fixed[entry].clone().unwrap()
+    }
+
+    /// Forget the map of `entry`, as when churn gives it a new name
+    pub fn reset(&mut self, entry: usize) {
+        if let Some(map) = self.fixed.get_mut(entry) {
+            *map = None;
+        }
+    }
+
+    fn draw<R: Rng>(&self, rng: &mut R) -> HashMap<String, String> {
+        let mut map = HashMap::with_capacity(self.config.keys);
+        // keys are picked without replacement, so maps always hold `keys`
+        while map.len() < self.config.keys {
+            let key = rng.gen_range(0, self.keys.len());
+            if map.contains_key(&self.keys[key]) {
+                continue;
+            }
+            map.insert(
+                self.keys[key].clone(),
+                rng.choose(&self.values[key]).unwrap().clone(),
+            );
+        }
+        map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use generator::seeded_rng;
+    use std::collections::HashSet;
+
+    fn config(fixed: bool) -> MetadataConfig {
+        MetadataConfig {
+            keys: 2,
+            key_cardinality: 5,
+            value_cardinality: 3,
+            fixed,
+        }
+    }
+
+    fn maps(fixed: bool, entry: usize) -> Vec<Vec<(String, String)>> {
+        let mut rng = seeded_rng(6);
+        let mut metadata = Metadata::new(&mut rng, &config(fixed), 10);
+        (0..1000)
+            .map(|_| {
+                let mut map: Vec<_> = metadata.generate(&mut rng, entry).into_iter().collect();
+                map.sort();
+                map
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fixed_maps_stay_with_their_entry() {
+        let maps = maps(true, 3);
+        assert_eq!(maps[0].len(), 2);
+        assert!(maps.iter().all(|map| *map == maps[0]));
+
+        let mut rng = seeded_rng(6);
+        let mut metadata = Metadata::new(&mut rng, &config(true), 10);
+        // a reset entry, as churn renames it, draws a new map
+        let before = metadata.generate(&mut rng, 1);
+        metadata.reset(1);
+        let after: Vec<_> = (0..20).map(|_| metadata.generate(&mut rng, 1)).collect();
+        assert!(after.iter().all(|map| *map == after[0]));
+        assert_ne!(after[0], before);
+    }
+
+    #[test]
+    fn per_point_maps_span_the_cardinality() {
+        let maps = maps(false, 3);
+        assert!(maps
+            .iter()
+            .all(|map| map.len() == 2 && map[0].0 != map[1].0));
+        let keys: HashSet<&String> = maps.iter().flat_map(|m| m.iter().map(|e| &e.0)).collect();
+        let pairs: HashSet<&(String, String)> = maps.iter().flat_map(|m| m.iter()).collect();
+        assert_eq!(keys.len(), 5);
+        assert_eq!(pairs.len(), 15);
+    }
+
+    #[test]
+    fn keys_cover_cardinality_at_least() {
+        let mut rng = seeded_rng(6);
+        let config = MetadataConfig {
+            keys: 4,
+            key_cardinality: 1,
+            ..config(false)
+        };
+        let mut metadata = Metadata::new(&mut rng, &config, 1);
+        assert_eq!(metadata.generate(&mut rng, 0).len(), 4);
+        let empty = Metadata::new(&mut rng, &MetadataConfig::default(), 1);
+        assert!(empty.is_empty());
+    }
+}
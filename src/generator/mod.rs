@@ -0,0 +1,86 @@
+//! Deterministic workload generation
+//!
+//! Every generator in this module owns its own seeded random number
+//! generator. Two generators built from the same seed and the same settings
+//! produce byte-identical output, which is what lets us replay the exact
+//! stream that tipped a server over.
+
+use rand::{thread_rng, Rng, SeedableRng, XorShiftRng};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub mod bins;
+pub mod churn;
+pub mod dogstatsd;
+pub mod logs;
+pub mod metadata;
+pub mod names;
+pub mod native;
+pub mod selection;
+pub mod statsd;
+pub mod timestamps;
+pub mod values;
+
+/// Pick a fresh seed for runs that did not ask for one
+pub fn random_seed() -> u64 {
+    thread_rng().gen::<u64>()
+}
+
+/// Build the random number generator backing a generator from a user seed
+///
+/// The seed is spread over the xorshift state with splitmix64 so that small,
+/// human-friendly seeds like 1 or 2 still produce unrelated streams.
+pub fn seeded_rng(seed: u64) -> XorShiftRng {
+    let mut state = seed;
+    let mut words = [0u32; 4];
+    for pair in words.chunks_mut(2) {
+        let z = splitmix64(&mut state);
+        pair[0] = (z >> 32) as u32;
+        pair[1] = z as u32;
+    }
+    // xorshift panics on an all-zero state
+    if words.iter().all(|w| *w == 0) {
+        words[0] = 0x9E37_79B9;
+    }
+    XorShiftRng::from_seed(words)
+}
+
+/// Derive the seed of an independent stream from a user seed
+///
+/// Used to give each worker of a multi-threaded emitter its own,
+/// reproducible, sequence of draws.
+pub fn stream_seed(seed: u64, stream: u64) -> u64 {
+    let mut state = seed ^ stream.wrapping_mul(0xD1B5_4A32_D192_ED03);
+    splitmix64(&mut state)
+}
+
+const LOWERCASE: &[char] = &[
+    'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r',
+    's', 't', 'u', 'v', 'w', 'x', 'y', 'z',
+];
+
+/// A lowercase word of between `min` and `max` letters
+fn word<R: Rng>(rng: &mut R, min: usize, max: usize) -> String {
+    word_of(rng, LOWERCASE, min, max)
+}
+
+/// A word of between `min` and `max` characters drawn from `letters`
+fn word_of<R: Rng>(rng: &mut R, letters: &[char], min: usize, max: usize) -> String {
+    let len = rng.gen_range(min, max + 1);
+    (0..len).map(|_| *rng.choose(letters).unwrap()).collect()
+}
+
+/// Milliseconds since the Unix epoch, 0 for a clock set before it
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
@@ -0,0 +1,332 @@
+//! Generation of metric names
+//!
+//! Names are built from a template of literal text and placeholders:
+//!
+//! * `{random:L}` is L random alphanumeric characters, the historical shape
+//! * `{word}` is a fresh lowercase word, `{word:N}` one of N distinct words
+//! * `{segments:A..B:C..D}` is A to B dot separated segments of C to D
+//!   characters each
+//!
+//! so `svc.{word:50}.{word:20}.latency` gives fifty hosts of twenty
+//! endpoints each. Words and segments can be drawn from non-ASCII letters,
+//! and a share of names can be corrupted with characters that protocols
+//! reserve, to exercise name parsing and interning in servers.
+
+use generator::word_of;
+use rand::Rng;
+use std::str::FromStr;
+
+/// One piece of a name template
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    Random(usize),
+    Word(Option<usize>),
+    Segments((usize, usize), (usize, usize)),
+}
+
+/// A name template, like `svc.{word:50}.{word:20}.latency`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Default for Template {
+    fn default() -> Template {
+        Template {
+            parts: vec![Part::Random(6)],
+        }
+    }
+}
+
+/// Parse `A..B` or `A` into an inclusive range
+fn parse_range(s: &str) -> Result<(usize, usize), String> {
+    let err = || format!("'{}' is not a count or a range like '3..5'", s);
+    let (low, high) = match s.find("..") {
+        Some(idx) => (&s[..idx], &s[idx + 2..]),
+        None => (s, s),
+    };
+    let low = low.parse::<usize>().map_err(|_| err())?;
+    let high = high.parse::<usize>().map_err(|_| err())?;
+    if low > high {
+        return Err(err());
+    }
+    Ok((low, high))
+}
+
+impl FromStr for Part {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Part, String> {
+        let args: Vec<&str> = s.split(':').collect();
+        let count = |arg: &str| {
+            arg.parse::<usize>()
+                .ok()
+                .filter(|n| *n > 0)
+                .ok_or_else(|| format!("'{}' in '{{{}}}' is not a positive count", arg, s))
+        };
+        match (args[0], args.len()) {
+            ("random", 2) => Ok(Part::Random(count(args[1])?)),
+            ("word", 1) => Ok(Part::Word(None)),
+            ("word", 2) => Ok(Part::Word(Some(count(args[1])?))),
+            ("segments", 3) => {
+                let segments = parse_range(args[1])?;
+                let length = parse_range(args[2])?;
+                if segments.0 == 0 || length.0 == 0 {
+                    return Err(format!("'{{{}}}' would produce empty segments", s));
+                }
+                Ok(Part::Segments(segments, length))
+            }
+            _ => Err(format!("unknown placeholder '{{{}}}'", s)),
+        }
+    }
+}
+
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Template, String> {
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while !rest.is_empty() {
+            match rest.find('{') {
+                Some(0) => {
+                    let close = rest
+                        .find('}')
+                        .ok_or_else(|| format!("unclosed placeholder in '{}'", s))?;
+                    parts.push(rest[1..close].parse::<Part>()?);
+                    rest = &rest[close + 1..];
+                }
+                Some(idx) => {
+                    parts.push(Part::Literal(rest[..idx].to_string()));
+                    rest = &rest[idx..];
+                }
+                None => {
+                    parts.push(Part::Literal(rest.to_string()));
+                    rest = "";
+                }
+            }
+        }
+        if parts.is_empty() {
+            return Err("empty name template".into());
+        }
+        Ok(Template { parts })
+    }
+}
+
+/// The letters words and segments are made of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Charset {
+    Ascii,
+    Unicode,
+}
+
+impl FromStr for Charset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Charset, String> {
+        match s {
+            "ascii" => Ok(Charset::Ascii),
+            "unicode" => Ok(Charset::Unicode),
+            _ => Err(format!("unknown charset '{}', expected ascii or unicode", s)),
+        }
+    }
+}
+
+impl Charset {
+    fn letters(self) -> &'static [char] {
+        match self {
+            Charset::Ascii => ASCII,
+            Charset::Unicode => UNICODE,
+        }
+    }
+}
+
+const ASCII: &[char] = &[
+    'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r',
+    's', 't', 'u', 'v', 'w', 'x', 'y', 'z', '0', '1', '2', '3', '4', '5', '6', '7', '8', '9',
+];
+
+// a mix of two, three and four byte UTF-8 sequences
+const UNICODE: &[char] = &[
+    'a', 'e', 'o', 'ä', 'ö', 'ü', 'ß', 'é', 'ñ', 'ç', 'ø', 'λ', 'π', 'Ω', 'ж', 'д', 'ש', 'ع',
+    '中', '文', '字', '日', '本', '語', '한', '글', '🙂', '🚀', '🔥',
+];
+
+// characters statsd and most line protocols reserve, plus control bytes
+const INVALID: &[char] = &[':', '|', '@', '#', ',', ' ', '\t', '=', '\u{0}', '\u{7f}'];
+
+/// Settings shaping generated metric names
+#[derive(Debug, Clone, PartialEq)]
+pub struct NameConfig {
+    /// What names look like
+    pub template: Template,
+    /// The letters words and segments are made of
+    pub charset: Charset,
+    /// Percentage of names corrupted with a reserved character
+    pub invalid: u32,
+}
+
+impl Default for NameConfig {
+    fn default() -> NameConfig {
+        NameConfig {
+            template: Template::default(),
+            charset: Charset::Ascii,
+            invalid: 0,
+        }
+    }
+}
+
+/// Produces metric names following a `NameConfig`
+#[derive(Debug, Clone)]
+pub struct Namer {
+    config: NameConfig,
+    // the distinct values of each `{word:N}`, drawn on first use
+    words: Vec<Vec<String>>,
+}
+
+impl Namer {
+    /// Create a namer for `config`
+    pub fn new(config: &NameConfig) -> Namer {
+        Namer {
+            config: config.clone(),
+            words: vec![Vec::new(); config.template.parts.len()],
+        }
+    }
+
+    /// Produce a name
+    pub fn name<R: Rng>(&mut self, rng: &mut R) -> String {
+        let mut name = String::new();
+        let letters = self.config.charset.letters();
+        for (idx, part) in self.config.template.parts.iter().enumerate() {
+            match *part {
+                Part::Literal(ref text) => name.push_str(text),
+                Part::Random(len) => name.extend(rng.gen_ascii_chars().take(len)),
+                Part::Word(None) => name.push_str(&word_of(rng, letters, 3, 10)),
+                Part::Word(Some(cardinality)) => {
+                    if self.words[idx].is_empty() {
+                        self.words[idx] = (0..cardinality)
+                            .map(|_| word_of(rng, letters, 3, 10))
+                            .collect();
+                    }
+                    name.push_str(rng.choose(&self.words[idx]).unwrap());
+                }
+                Part::Segments(segments, length) => {
+                    let count = rng.gen_range(segments.0, segments.1 + 1);
+                    for i in 0..count {
+                        if i > 0 {
+                            name.push('.');
+                        }
+                        name.push_str(&word_of(rng, letters, length.0, length.1));
+                    }
+                }
+            }
+        }
+        if self.config.invalid > 0 && rng.gen_range(0, 100) < self.config.invalid {
+            let chars: Vec<char> = name.chars().collect();
+            let at = rng.gen_range(0, chars.len() + 1);
+            let bad = *rng.choose(INVALID).unwrap();
+            name = chars[..at]
+                .iter()
+                .chain(Some(&bad))
+                .chain(chars[at..].iter())
+                .collect();
+        }
+        name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use generator::seeded_rng;
+    use std::collections::HashSet;
+
+    fn namer(template: &str, charset: Charset, invalid: u32) -> Namer {
+        Namer::new(&NameConfig {
+            template: template.parse().unwrap(),
+            charset,
+            invalid,
+        })
+    }
+
+    #[test]
+    fn templates_parse() {
+        let template = "svc.{word:50}.{segments:1..3:2}.{random:4}".parse::<Template>();
+        assert_eq!(
+            template.unwrap().parts,
+            vec![
+                Part::Literal("svc.".to_string()),
+                Part::Word(Some(50)),
+                Part::Literal(".".to_string()),
+                Part::Segments((1, 3), (2, 2)),
+                Part::Literal(".".to_string()),
+                Part::Random(4),
+            ]
+        );
+        assert!("".parse::<Template>().is_err());
+        assert!("a.{word".parse::<Template>().is_err());
+        assert!("{word:0}".parse::<Template>().is_err());
+        assert!("{segments:0..2:3}".parse::<Template>().is_err());
+        assert!("{segments:1..2:4..3}".parse::<Template>().is_err());
+        assert!("{nope}".parse::<Template>().is_err());
+    }
+
+    #[test]
+    fn segments_follow_their_ranges() {
+        let mut rng = seeded_rng(3);
+        let mut namer = namer("p.{segments:2..4:3..5}", Charset::Ascii, 0);
+        let mut counts = HashSet::new();
+        for _ in 0..500 {
+            let name = namer.name(&mut rng);
+            let segments: Vec<&str> = name["p.".len()..].split('.').collect();
+            counts.insert(segments.len());
+            for segment in segments {
+                assert!(3 <= segment.len() && segment.len() <= 5, "{}", name);
+                assert!(segment.chars().all(|c| ASCII.contains(&c)));
+            }
+        }
+        assert_eq!(counts, [2, 3, 4].iter().cloned().collect());
+    }
+
+    #[test]
+    fn words_keep_their_cardinality() {
+        let mut rng = seeded_rng(4);
+        let mut namer = namer("{word:5}.{word:3}", Charset::Unicode, 0);
+        let names: HashSet<String> = (0..2000).map(|_| namer.name(&mut rng)).collect();
+        assert_eq!(names.len(), 15);
+        assert!(names
+            .iter()
+            .all(|name| name.chars().all(|c| c == '.' || UNICODE.contains(&c))));
+
+        let mut fresh = self::namer("{word}", Charset::Ascii, 0);
+        let names: HashSet<String> = (0..200).map(|_| fresh.name(&mut rng)).collect();
+        assert!(names.len() > 190);
+    }
+
+    #[test]
+    fn invalid_names_carry_one_reserved_character() {
+        let mut rng = seeded_rng(5);
+        let mut namer = namer("{word}", Charset::Ascii, 30);
+        let names: Vec<String> = (0..5000).map(|_| namer.name(&mut rng)).collect();
+        let invalid = names
+            .iter()
+            .filter(|name| name.chars().any(|c| INVALID.contains(&c)))
+            .count();
+        assert!(1300 < invalid && invalid < 1700, "{}", invalid);
+        for name in names {
+            assert!(name.chars().filter(|c| INVALID.contains(c)).count() <= 1);
+        }
+    }
+
+    #[test]
+    fn names_are_reproducible() {
+        let names = |seed| {
+            let mut rng = seeded_rng(seed);
+            let mut namer = namer("a.{word:10}.{segments:1..3:2..6}", Charset::Unicode, 10);
+            (0..100).map(|_| namer.name(&mut rng)).collect::<Vec<_>>()
+        };
+        assert_eq!(names(7), names(7));
+        assert!(names(7) != names(8));
+    }
+}
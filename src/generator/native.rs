@@ -0,0 +1,340 @@
+//! Generation of cernan native `Payload`s
+
+use generator::bins::Bounds;
+use generator::churn::{Churn, Schedule};
+use generator::logs::{LogConfig, Logs};
+use generator::metadata::{Metadata, MetadataConfig};
+use generator::names::{NameConfig, Namer};
+use generator::selection::{Picker, Selection};
+use generator::seeded_rng;
+use generator::timestamps::{Clock, Timestamps};
+use generator::values::{parse_assignments, Distribution, Sampler};
+use protobuf::repeated::RepeatedField;
+use protocols::native::*;
+use rand::{Rng, XorShiftRng};
+use std::str::FromStr;
+
+const METHODS: [(&str, AggregationMethod); 4] = [
+    ("sum", AggregationMethod::SUM),
+    ("set", AggregationMethod::SET),
+    ("summarize", AggregationMethod::SUMMARIZE),
+    ("bin", AggregationMethod::BIN),
+];
+
+fn method_index(method: AggregationMethod) -> usize {
+    METHODS.iter().position(|m| m.1 == method).unwrap()
+}
+
+/// The distribution each aggregation method draws its samples from
+///
+/// Written as comma separated `method=distribution` pairs, methods being
+/// `sum`, `set`, `summarize` and `bin`, for instance
+/// `summarize=lognormal:0:1,sum=monotonic:10`. Methods left out draw reals
+/// from 0 up to 1.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Values {
+    distributions: [Distribution; 4],
+}
+
+impl Values {
+    /// The distribution `method` draws its samples from
+    pub fn distribution(&self, method: AggregationMethod) -> Distribution {
+        self.distributions[method_index(method)]
+    }
+}
+
+impl Default for Values {
+    fn default() -> Values {
+        Values {
+            distributions: [Distribution::Uniform(0.0, 1.0); 4],
+        }
+    }
+}
+
+impl FromStr for Values {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Values, String> {
+        let mut values = Values::default();
+        for (method, distribution) in parse_assignments(s)? {
+            let idx = METHODS
+                .iter()
+                .position(|m| m.0 == method)
+                .ok_or_else(|| format!("unknown aggregation method '{}'", method))?;
+            values.distributions[idx] = distribution;
+        }
+        Ok(values)
+    }
+}
+
+/// Settings shaping a `NativeGenerator`'s output
+#[derive(Debug, Clone)]
+pub struct NativeConfig {
+    /// Total number of distinct metric names
+    pub pool_size: usize,
+    /// Average number of points in a payload
+    pub payload_limit: u32,
+    /// What metric names look like
+    pub names: NameConfig,
+    /// The pace at which pool entries are replaced by new names
+    pub churn: Option<Churn>,
+    /// How often each pool entry is picked
+    pub selection: Selection,
+    /// The distribution each aggregation method draws its samples from
+    pub values: Values,
+    /// Percentage of samples replaced by NaN, infinities and extremes
+    pub adversarial: u32,
+    /// Bound sets handed out to `BIN` metrics, which then draw samples
+    /// spread evenly over their bins
+    pub bins: Vec<Bounds>,
+    /// What metadata points carry
+    pub metadata: MetadataConfig,
+    /// How points are timestamped, left to the server when None
+    pub timestamps: Option<Timestamps>,
+    /// What log lines look like and the percentage of payload entries
+    /// that are log lines rather than points
+    pub logs: Option<(LogConfig, u32)>,
+}
+
+/// Produces native payloads from a fixed pool of metric names
+pub struct NativeGenerator {
+    rng: XorShiftRng,
+    namer: Namer,
+    pool: Vec<(String, AggregationMethod, bool)>,
+    picker: Picker,
+    samplers: Vec<Sampler>,
+    bins: Vec<Bounds>,
+    // the index in `bins` of each pool entry's bounds
+    bin_sets: Vec<Option<usize>>,
+    metadata: Metadata,
+    clock: Option<Clock>,
+    logs: Option<(Logs, u32)>,
+    payload_limit: u32,
+    churn: Option<Schedule>,
+}
+
+impl NativeGenerator {
+    /// Create a new generator, filling its pool of metric names and, when
+    /// producing logs, its set of log paths
+    pub fn new(seed: u64, config: &NativeConfig) -> NativeGenerator {
+        let mut rng = seeded_rng(seed);
+        let pool_size = config.pool_size;
+        let mut namer = Namer::new(&config.names);
+
+        let types = [
+            AggregationMethod::BIN,
+            AggregationMethod::SET,
+            AggregationMethod::SUM,
+            AggregationMethod::SUMMARIZE,
+        ];
+
+        let mut pool: Vec<(String, AggregationMethod, bool)> = Vec::with_capacity(pool_size);
+        let mut attempts = 10;
+        while attempts > 0 {
+            for _ in 0..pool_size {
+                let metric_name = namer.name(&mut rng);
+                match pool.binary_search_by(|probe| probe.0.cmp(&metric_name)) {
+                    Ok(_) => {}
+                    Err(idx) => {
+                        let metric_type: &AggregationMethod = rng.choose(&types).unwrap();
+                        let persist: bool = rng.gen::<bool>();
+                        pool.insert(idx, (metric_name.clone(), *metric_type, persist));
+                    }
+                };
+            }
+            if pool.len() == pool_size {
+                break;
+            }
+            attempts -= 1;
+        }
+
+        let picker = Picker::new(config.selection, pool.len());
+        let bin_sets = pool
+            .iter()
+            .map(|e| match e.1 {
+                AggregationMethod::BIN if !config.bins.is_empty() => {
+                    Some(rng.gen_range(0, config.bins.len()))
+                }
+                _ => None,
+            })
+            .collect();
+        let metadata = Metadata::new(&mut rng, &config.metadata, pool.len());
+        let logs = config
+            .logs
+            .as_ref()
+            .map(|&(ref logs, ratio)| (Logs::new(&mut rng, logs), ratio));
+        let samplers = METHODS
+            .iter()
+            .map(|m| Sampler::new(config.values.distribution(m.1), config.adversarial, pool.len()))
+            .collect();
+        let churn = config
+            .churn
+            .map(|churn| Schedule::new(churn, pool.iter().map(|e| &e.0)));
+
+        NativeGenerator {
+            rng,
+            namer,
+            pool,
+            picker,
+            samplers,
+            bins: config.bins.clone(),
+            bin_sets,
+            metadata,
+            clock: config.timestamps.as_ref().map(Clock::new),
+            logs,
+            payload_limit: config.payload_limit,
+            churn,
+        }
+    }
+
+    /// Produce the next payload
+    pub fn next_payload(&mut self) -> Payload {
+        let mut points = Vec::new();
+        let mut lines = Vec::new();
+        loop {
+            let log = match self.logs {
+                Some((_, ratio)) => self.rng.gen_range(0, 100) < ratio,
+                None => false,
+            };
+            if log {
+                let rng = &mut self.rng;
+                let timestamp_ms = self.clock.as_mut().map(|clock| clock.next(rng));
+                let logs = &mut self.logs.as_mut().unwrap().0;
+                lines.push(logs.next_line(&mut self.rng, timestamp_ms));
+            } else {
+                points.push(self.next_point());
+            }
+
+            if self.rng.gen_weighted_bool(self.payload_limit) {
+                break;
+            }
+        }
+
+        if let Some(ref mut churn) = self.churn {
+            for _ in 0..churn.due(points.len()) {
+                let entry = self.rng.gen_range(0, self.pool.len());
+                let old = &mut self.pool[entry].0;
+                if let Some(name) = churn.replace(&mut self.rng, &mut self.namer, old) {
+                    *old = name;
+                    for sampler in &mut self.samplers {
+                        sampler.reset(entry);
+                    }
+                    self.metadata.reset(entry);
+                }
+            }
+        }
+
+        let mut pyld = Payload::new();
+        pyld.set_points(RepeatedField::from_vec(points));
+        pyld.set_lines(RepeatedField::from_vec(lines));
+        pyld
+    }
+
+    fn next_point(&mut self) -> Telemetry {
+        let entry = self.picker.pick(&mut self.rng);
+        let choice = &self.pool[entry];
+        let metric_name = &choice.0;
+        let metric_type = &choice.1;
+        let metric_persist = &choice.2;
+
+        let mut point = Telemetry::new();
+        point.set_name(metric_name.to_string());
+        point.set_persisted(*metric_persist);
+        point.set_method(*metric_type);
+        if let Some(ref mut clock) = self.clock {
+            point.set_timestamp_ms(clock.next(&mut self.rng));
+        }
+        if !self.metadata.is_empty() {
+            point.set_metadata(self.metadata.generate(&mut self.rng, entry));
+        }
+        let sampler = &mut self.samplers[method_index(*metric_type)];
+        let bins = &self.bins;
+        let bounds = self.bin_sets[entry].map(|set| &bins[set]);
+        if let Some(bounds) = bounds {
+            point.set_bin_bounds(bounds.bounds().to_vec());
+        }
+        let mut vals = Vec::new();
+        for _ in 0..self.rng.gen_range(0, 50) {
+            vals.push(match bounds {
+                Some(bounds) => bounds.sample(&mut self.rng),
+                None => sampler.sample(&mut self.rng, entry),
+            });
+        }
+        point.set_samples(vals);
+        point
+    }
+
+    /// Number of pool entries retired by churn so far
+    pub fn retired(&self) -> usize {
+        self.churn.as_ref().map_or(0, |churn| churn.retired())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use generator::logs::Template as LogTemplate;
+    use protobuf::Message;
+    use protocols::native::Payload;
+
+    fn config() -> NativeConfig {
+        NativeConfig {
+            pool_size: 100,
+            payload_limit: 20,
+            names: NameConfig::default(),
+            churn: Some("1/10".parse().unwrap()),
+            selection: Selection::default(),
+            values: Values::default(),
+            // NaN samples never compare equal
+            adversarial: 0,
+            bins: vec!["linear:0:10:5".parse().unwrap()],
+            metadata: MetadataConfig {
+                keys: 2,
+                key_cardinality: 4,
+                value_cardinality: 10,
+                fixed: true,
+            },
+            timestamps: None,
+            // plain lines, as the other templates read the wall clock
+            logs: Some((
+                LogConfig {
+                    template: LogTemplate::Plain,
+                    ..LogConfig::default()
+                },
+                20,
+            )),
+        }
+    }
+
+    fn payloads(seed: u64, config: &NativeConfig) -> Vec<Payload> {
+        let mut gen = NativeGenerator::new(seed, config);
+        (0..200).map(|_| gen.next_payload()).collect()
+    }
+
+    #[test]
+    fn same_seed_same_payloads() {
+        let config = config();
+        assert_eq!(payloads(42, &config), payloads(42, &config));
+        assert_ne!(payloads(42, &config), payloads(43, &config));
+    }
+
+    #[test]
+    fn same_seed_same_bytes() {
+        let config = NativeConfig {
+            metadata: MetadataConfig {
+                keys: 8,
+                key_cardinality: 8,
+                value_cardinality: 10,
+                fixed: false,
+            },
+            ..config()
+        };
+        let bytes = |seed| {
+            payloads(seed, &config)
+                .iter()
+                .map(|payload| payload.write_to_bytes().unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(bytes(42), bytes(42));
+    }
+}
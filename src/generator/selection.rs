@@ -0,0 +1,165 @@
+//! Selection of pool entries
+//!
+//! Real traffic is skewed: a handful of metrics get most of the points. A
+//! selection distribution decides how often each pool entry is picked, so
+//! aggregator hash tables and locks see the contention they see in
+//! production rather than an even spread.
+
+use rand::Rng;
+use std::str::FromStr;
+
+/// How pool entries are picked
+///
+/// Written `uniform`, `zipf:S` for a Zipf distribution of exponent S, or
+/// `hot:P:T` for P percent of the pool taking T percent of picks.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Selection {
+    #[default]
+    Uniform,
+    Zipf(f64),
+    Hot(f64, f64),
+}
+
+impl FromStr for Selection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Selection, String> {
+        let args: Vec<&str> = s.split(':').collect();
+        let number = |arg: &str, low: f64, high: f64| {
+            arg.parse::<f64>()
+                .ok()
+                .filter(|n| *n >= low && *n <= high)
+                .ok_or_else(|| format!("'{}' in '{}' is not within {}..{}", arg, s, low, high))
+        };
+        match (args[0], args.len()) {
+            ("uniform", 1) => Ok(Selection::Uniform),
+            ("zipf", 2) => Ok(Selection::Zipf(number(args[1], 0.0, 100.0)?)),
+            ("hot", 3) => Ok(Selection::Hot(
+                number(args[1], 0.0, 100.0)?,
+                number(args[2], 0.0, 100.0)?,
+            )),
+            _ => Err(format!(
+                "unknown selection '{}', expected uniform, zipf:S or hot:P:T",
+                s
+            )),
+        }
+    }
+}
+
+/// Picks indexes into a pool following a `Selection`
+#[derive(Debug, Clone)]
+pub struct Picker {
+    len: usize,
+    kind: Kind,
+}
+
+#[derive(Debug, Clone)]
+enum Kind {
+    Uniform,
+    // cumulative weights of each rank
+    Cumulative(Vec<f64>),
+    // number of hot entries and the chance of picking one of them
+    Hot(usize, f64),
+}
+
+impl Picker {
+    /// Create a picker over a pool of `len` entries
+    pub fn new(selection: Selection, len: usize) -> Picker {
+        let kind = match selection {
+            Selection::Uniform => Kind::Uniform,
+            Selection::Zipf(exponent) => {
+                let mut total = 0.0;
+                let cumulative = (0..len)
+                    .map(|rank| {
+                        total += 1.0 / ((rank + 1) as f64).powf(exponent);
+                        total
+                    })
+                    .collect();
+                Kind::Cumulative(cumulative)
+            }
+            Selection::Hot(percent, traffic) => {
+                let hot = ((len as f64 * percent / 100.0).ceil() as usize).clamp(1, len.max(1));
+                Kind::Hot(hot, traffic / 100.0)
+            }
+        };
+        Picker { len, kind }
+    }
+
+    /// Pick the index of an entry
+    pub fn pick<R: Rng>(&self, rng: &mut R) -> usize {
+        match self.kind {
+            Kind::Uniform => rng.gen_range(0, self.len),
+            Kind::Cumulative(ref cumulative) => {
+                let target = rng.gen::<f64>() * cumulative[self.len - 1];
+                match cumulative.binary_search_by(|w| w.partial_cmp(&target).unwrap()) {
+                    Ok(idx) | Err(idx) => idx.min(self.len - 1),
+                }
+            }
+            Kind::Hot(hot, chance) => {
+                if hot == self.len || rng.gen::<f64>() < chance {
+                    rng.gen_range(0, hot)
+                } else {
+                    rng.gen_range(hot, self.len)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use generator::seeded_rng;
+
+    fn counts(selection: Selection, len: usize, draws: usize) -> Vec<usize> {
+        let picker = Picker::new(selection, len);
+        let mut rng = seeded_rng(11);
+        let mut counts = vec![0; len];
+        for _ in 0..draws {
+            counts[picker.pick(&mut rng)] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn selections_parse() {
+        assert_eq!("uniform".parse(), Ok(Selection::Uniform));
+        assert_eq!("zipf:1.2".parse(), Ok(Selection::Zipf(1.2)));
+        assert_eq!("hot:1:90".parse(), Ok(Selection::Hot(1.0, 90.0)));
+        assert!("zipf".parse::<Selection>().is_err());
+        assert!("zipf:-1".parse::<Selection>().is_err());
+        assert!("zipf:x".parse::<Selection>().is_err());
+        assert!("hot:10".parse::<Selection>().is_err());
+        assert!("hot:10:101".parse::<Selection>().is_err());
+        assert!("normal".parse::<Selection>().is_err());
+    }
+
+    #[test]
+    fn hot_entries_take_their_share() {
+        let counts = counts(Selection::Hot(10.0, 80.0), 100, 100_000);
+        let hot: usize = counts[..10].iter().sum();
+        assert!((hot as f64 / 100_000.0 - 0.8).abs() < 0.01, "{}", hot);
+        // a pool too small to split is all hot
+        let counts = self::counts(Selection::Hot(10.0, 0.0), 1, 100);
+        assert_eq!(counts, vec![100]);
+    }
+
+    #[test]
+    fn zipf_ranks_fall_off() {
+        let counts = counts(Selection::Zipf(1.0), 50, 100_000);
+        // with exponent 1 rank r is picked 1/r as often as the first
+        let ratio = counts[0] as f64 / counts[1] as f64;
+        assert!((ratio - 2.0).abs() < 0.1, "{}", ratio);
+        let ratio = counts[0] as f64 / counts[9] as f64;
+        assert!((ratio - 10.0).abs() < 1.0, "{}", ratio);
+        // exponent 0 is uniform
+        let counts = self::counts(Selection::Zipf(0.0), 4, 40_000);
+        assert!(counts.iter().all(|c| (*c as i64 - 10_000).abs() < 500));
+    }
+
+    #[test]
+    fn uniform_covers_the_pool() {
+        let counts = counts(Selection::Uniform, 20, 40_000);
+        assert!(counts.iter().all(|c| (*c as i64 - 2_000).abs() < 200));
+    }
+}
@@ -0,0 +1,582 @@
+//! Generation of statsd lines
+
+use generator::churn::{Churn, Schedule};
+use generator::dogstatsd::{DogStatsd, Extensions, Kind};
+use generator::names::{NameConfig, Namer};
+use generator::selection::{Picker, Selection};
+use generator::values::{parse_assignments, Distribution, Sampler};
+use generator::{seeded_rng, stream_seed};
+use rand::{Rng, XorShiftRng};
+use std::fmt;
+use std::str::FromStr;
+
+/// The statsd metric types
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MetricType {
+    Gauge,
+    Counter,
+    Timer,
+    Histogram,
+    Set,
+    Distribution,
+}
+
+impl MetricType {
+    /// Every type, in the order a `Mix` draws them
+    pub const ALL: [MetricType; 6] = [
+        MetricType::Gauge,
+        MetricType::Counter,
+        MetricType::Timer,
+        MetricType::Histogram,
+        MetricType::Set,
+        MetricType::Distribution,
+    ];
+
+    /// The type's on-wire suffix, as in `name:1|c`
+    pub fn suffix(&self) -> &'static str {
+        match *self {
+            MetricType::Gauge => "g",
+            MetricType::Counter => "c",
+            MetricType::Timer => "ms",
+            MetricType::Histogram => "h",
+            MetricType::Set => "s",
+            MetricType::Distribution => "d",
+        }
+    }
+
+    fn index(&self) -> usize {
+        MetricType::ALL.iter().position(|t| t == self).unwrap()
+    }
+}
+
+impl fmt::Display for MetricType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            MetricType::Gauge => "GAUGES",
+            MetricType::Counter => "COUNTERS",
+            MetricType::Timer => "TIMERS",
+            MetricType::Histogram => "HISTOGRAMS",
+            MetricType::Set => "SETS",
+            MetricType::Distribution => "DISTRIBUTIONS",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for MetricType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<MetricType, String> {
+        MetricType::ALL
+            .iter()
+            .find(|t| t.suffix() == s)
+            .cloned()
+            .ok_or_else(|| format!("unknown metric type '{}'", s))
+    }
+}
+
+/// Relative weights of each metric type in the pool
+///
+/// Written as comma separated `suffix=weight` pairs, for instance
+/// `c=50,g=45,ms=3,h=2,s=0,d=0`. Types left out get no weight.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mix {
+    weights: [u32; 6],
+}
+
+impl Mix {
+    /// The weight given to `metric_type`
+    pub fn weight(&self, metric_type: MetricType) -> u32 {
+        self.weights[metric_type.index()]
+    }
+
+    fn choose<R: Rng>(&self, rng: &mut R) -> MetricType {
+        let total: u32 = self.weights.iter().sum();
+        let mut pick = rng.gen_range(0, total);
+        for (metric_type, weight) in MetricType::ALL.iter().zip(self.weights.iter()) {
+            if pick < *weight {
+                return *metric_type;
+            }
+            pick -= *weight;
+        }
+        unreachable!()
+    }
+}
+
+impl Default for Mix {
+    /// Roughly 50% counters, 45% gauges, 3% timers and 2% histograms
+    fn default() -> Mix {
+        Mix {
+            weights: [45, 50, 3, 2, 0, 0],
+        }
+    }
+}
+
+impl FromStr for Mix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Mix, String> {
+        let mut weights = [0; 6];
+        for pair in s.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+            let mut parts = pair.splitn(2, '=');
+            let metric_type = parts.next().unwrap().trim().parse::<MetricType>()?;
+            let weight = match parts.next().map(|w| w.trim().parse::<u32>()) {
+                Some(Ok(w)) => w,
+                _ => return Err(format!("'{}' is not a 'type=weight' pair", pair)),
+            };
+            weights[metric_type.index()] = weight;
+        }
+        if weights.iter().all(|w| *w == 0) {
+            return Err("mix gives no weight to any metric type".into());
+        }
+        Ok(Mix { weights })
+    }
+}
+
+/// The sample rate each metric type is sent with
+///
+/// Written as comma separated `suffix=rate` pairs, for instance
+/// `c=0.1,ms=0.5`. Types left out are sent unsampled, without a `|@` field.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleRates {
+    rates: [f64; 6],
+}
+
+impl SampleRates {
+    /// The sample rate of `metric_type`, 1 when unsampled
+    pub fn rate(&self, metric_type: MetricType) -> f64 {
+        self.rates[metric_type.index()]
+    }
+}
+
+impl Default for SampleRates {
+    fn default() -> SampleRates {
+        SampleRates { rates: [1.0; 6] }
+    }
+}
+
+impl FromStr for SampleRates {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SampleRates, String> {
+        let mut rates = [1.0; 6];
+        for pair in s.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+            let mut parts = pair.splitn(2, '=');
+            let metric_type = parts.next().unwrap().trim().parse::<MetricType>()?;
+            let rate = match parts.next().map(|r| r.trim().parse::<f64>()) {
+                Some(Ok(r)) if r > 0.0 && r <= 1.0 => r,
+                Some(Ok(_)) => return Err(format!("sample rate in '{}' is not in (0, 1]", pair)),
+                _ => return Err(format!("'{}' is not a 'type=rate' pair", pair)),
+            };
+            rates[metric_type.index()] = rate;
+        }
+        Ok(SampleRates { rates })
+    }
+}
+
+/// The distribution each metric type draws its values from
+///
+/// Written as comma separated `suffix=distribution` pairs, for instance
+/// `c=int:1..10,g=walk:100:5,ms=lognormal:3:1`. Types left out draw
+/// integers from 0 to 999.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Values {
+    distributions: [Distribution; 6],
+}
+
+impl Values {
+    /// The distribution `metric_type` draws its values from
+    pub fn distribution(&self, metric_type: MetricType) -> Distribution {
+        self.distributions[metric_type.index()]
+    }
+}
+
+impl Default for Values {
+    fn default() -> Values {
+        Values {
+            distributions: [Distribution::Int(0, 999); 6],
+        }
+    }
+}
+
+impl FromStr for Values {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Values, String> {
+        let mut values = Values::default();
+        for (metric_type, distribution) in parse_assignments(s)? {
+            values.distributions[metric_type.parse::<MetricType>()?.index()] = distribution;
+        }
+        Ok(values)
+    }
+}
+
+/// Format a value the way a statsd client would, integers without a
+/// fraction and very large or small magnitudes in exponent notation
+fn format_value(value: f64) -> String {
+    let magnitude = value.abs();
+    if value.is_nan() || value.is_infinite() {
+        format!("{}", value)
+    } else if value == 0.0 && value.is_sign_negative() {
+        // the integer conversion below would drop the sign
+        "-0".to_string()
+    } else if value.fract() == 0.0 && magnitude < 1e15 {
+        format!("{}", value as i64)
+    } else if !(1e-4..1e15).contains(&magnitude) {
+        format!("{:e}", value)
+    } else {
+        format!("{}", value)
+    }
+}
+
+/// What a generator has produced, or a listener received, per metric type
+///
+/// The effective count is what a server should report after undoing the
+/// sample rate: the sum of values for counters, the number of samples for
+/// timers, histograms and distributions. Gauges and sets are not rescaled
+/// by servers, so their effective count is simply their line count.
+///
+/// Lines carrying NaN or an infinity are counted apart, servers rejecting
+/// them and a single one poisoning a sum for good. So are lines whose
+/// extreme values would take the sum past the range of `f64`.
+#[derive(Debug, Clone, Default)]
+pub struct Tally {
+    lines: [usize; 6],
+    effective: [f64; 6],
+    non_finite: [usize; 6],
+}
+
+impl Tally {
+    /// Lines of `metric_type` produced
+    pub fn lines(&self, metric_type: MetricType) -> usize {
+        self.lines[metric_type.index()]
+    }
+
+    /// Effective count of `metric_type` after rescaling by sample rate
+    pub fn effective(&self, metric_type: MetricType) -> f64 {
+        self.effective[metric_type.index()]
+    }
+
+    /// Lines of `metric_type` left out for a value, or a sum, that is not
+    /// finite
+    pub fn non_finite(&self, metric_type: MetricType) -> usize {
+        self.non_finite[metric_type.index()]
+    }
+
+    /// Fold `other` into this tally
+    pub fn merge(&mut self, other: &Tally) {
+        for i in 0..6 {
+            self.lines[i] += other.lines[i];
+            self.effective[i] += other.effective[i];
+            self.non_finite[i] += other.non_finite[i];
+        }
+    }
+
+    /// Count `lines` lines of `metric_type` carrying `value` at sample `rate`
+    pub fn record(&mut self, metric_type: MetricType, lines: usize, value: f64, rate: f64) {
+        let lines_f = lines as f64;
+        let effective = match metric_type {
+            MetricType::Counter => lines_f * value / rate,
+            MetricType::Timer | MetricType::Histogram | MetricType::Distribution => {
+                lines_f / rate
+            }
+            MetricType::Gauge | MetricType::Set => lines_f,
+        };
+        let sum = self.effective[metric_type.index()] + effective;
+        if !value.is_finite() || !sum.is_finite() {
+            self.non_finite[metric_type.index()] += lines;
+            return;
+        }
+        self.lines[metric_type.index()] += lines;
+        self.effective[metric_type.index()] = sum;
+    }
+}
+
+/// Settings shaping a `StatsdGenerator`'s output
+#[derive(Debug, Clone, Default)]
+pub struct StatsdConfig {
+    /// Total number of distinct metric names
+    pub pool_size: usize,
+    /// What metric names look like
+    pub names: NameConfig,
+    /// The pace at which pool entries are replaced by new names
+    pub churn: Option<Churn>,
+    /// How often each pool entry is picked
+    pub selection: Selection,
+    /// Relative weights of each metric type in the pool
+    pub mix: Mix,
+    /// The sample rate each metric type is sent with
+    pub sample_rates: SampleRates,
+    /// Speak DogStatsD rather than plain statsd
+    pub dogstatsd: Option<DogStatsd>,
+    /// The distribution each metric type draws its values from
+    pub values: Values,
+    /// Percentage of values replaced by NaN, infinities and extremes
+    pub adversarial: u32,
+}
+
+/// Produces statsd packets from a fixed pool of metric names
+pub struct StatsdGenerator {
+    seed: u64,
+    rng: XorShiftRng,
+    namer: Namer,
+    pool: Vec<(String, MetricType)>,
+    picker: Picker,
+    samplers: Vec<Sampler>,
+    counts: [usize; 6],
+    sample_rates: SampleRates,
+    extensions: Option<Extensions>,
+    churn: Option<Schedule>,
+    tally: Tally,
+}
+
+impl StatsdGenerator {
+    /// Create a new generator, filling its pool of metric names
+    pub fn new(seed: u64, config: &StatsdConfig) -> StatsdGenerator {
+        let mut rng = seeded_rng(seed);
+        let pool_size = config.pool_size;
+        let mut namer = Namer::new(&config.names);
+
+        let mut pool: Vec<(String, MetricType)> = Vec::with_capacity(pool_size);
+        let mut attempts = 10;
+        let mut counts = [0; 6];
+        while attempts > 0 {
+            for _ in 0..pool_size {
+                let metric_name = namer.name(&mut rng);
+                match pool.binary_search_by(|probe| probe.0.cmp(&metric_name)) {
+                    Ok(_) => {}
+                    Err(idx) => {
+                        let metric_type = config.mix.choose(&mut rng);
+                        counts[metric_type.index()] += 1;
+                        pool.insert(idx, (metric_name.clone(), metric_type));
+                    }
+                };
+            }
+            if pool.len() == pool_size {
+                break;
+            }
+            attempts -= 1;
+        }
+
+        let extensions = config
+            .dogstatsd
+            .as_ref()
+            .map(|dogstatsd| Extensions::new(&mut rng, dogstatsd, pool.len()));
+
+        let picker = Picker::new(config.selection, pool.len());
+        let churn = config
+            .churn
+            .map(|churn| Schedule::new(churn, pool.iter().map(|e| &e.0)));
+
+        let samplers = MetricType::ALL
+            .iter()
+            .map(|t| Sampler::new(config.values.distribution(*t), config.adversarial, pool.len()))
+            .collect();
+
+        StatsdGenerator {
+            seed,
+            rng,
+            namer,
+            pool,
+            picker,
+            samplers,
+            counts,
+            sample_rates: config.sample_rates.clone(),
+            extensions,
+            churn,
+            tally: Tally::default(),
+        }
+    }
+
+    /// Number of pool entries of `metric_type`
+    pub fn count(&self, metric_type: MetricType) -> usize {
+        self.counts[metric_type.index()]
+    }
+
+    /// What this generator has produced so far
+    pub fn tally(&self) -> &Tally {
+        &self.tally
+    }
+
+    /// Number of pool entries retired by churn so far
+    pub fn retired(&self) -> usize {
+        self.churn.as_ref().map_or(0, |churn| churn.retired())
+    }
+
+    /// Create a generator sharing this one's pool but drawing from the
+    /// independent stream `stream`
+    pub fn fork(&self, stream: u64) -> StatsdGenerator {
+        StatsdGenerator {
+            seed: self.seed,
+            rng: seeded_rng(stream_seed(self.seed, stream)),
+            namer: self.namer.clone(),
+            pool: self.pool.clone(),
+            picker: self.picker.clone(),
+            samplers: self.samplers.clone(),
+            counts: self.counts,
+            sample_rates: self.sample_rates.clone(),
+            extensions: self.extensions.clone(),
+            churn: self.churn.clone(),
+            tally: Tally::default(),
+        }
+    }
+
+    /// Append one packet's worth of lines to `buf`
+    ///
+    /// Returns the number of lines appended.
+    pub fn fill_packet(&mut self, buf: &mut String) -> usize {
+        let entry = self.picker.pick(&mut self.rng);
+        let (ref metric_name, metric_type) = self.pool[entry];
+        let kind = match self.extensions {
+            Some(ref extensions) => extensions.kind(&mut self.rng),
+            None => Kind::Metric,
+        };
+        if kind != Kind::Metric {
+            let extensions = self.extensions.as_ref().unwrap();
+            if kind == Kind::Event {
+                extensions.event(&mut self.rng, entry, buf);
+            } else {
+                extensions.service_check(&mut self.rng, entry, metric_name, buf);
+            }
+            buf.push('\n');
+            self.churn(1);
+            return 1;
+        }
+
+        let val = self.samplers[metric_type.index()].sample(&mut self.rng, entry);
+        let rate = self.sample_rates.rate(metric_type);
+
+        let mut line = String::new();
+        line.push_str(metric_name);
+        line.push(':');
+        line.push_str(&format_value(val));
+        line.push('|');
+        line.push_str(metric_type.suffix());
+        if rate < 1.0 {
+            line.push_str(&format!("|@{}", rate));
+        }
+        if let Some(ref extensions) = self.extensions {
+            extensions.metric_fields(&mut self.rng, entry, &mut line);
+        }
+        line.push('\n');
+
+        let tot = self.rng.gen_range(1, 40);
+        for _ in 0..tot {
+            buf.push_str(&line);
+        }
+        self.tally.record(metric_type, tot, val, rate);
+        self.churn(tot);
+        tot
+    }
+
+    /// Replace the pool entries churn has made due after `lines` more lines
+    fn churn(&mut self, lines: usize) {
+        if let Some(ref mut churn) = self.churn {
+            for _ in 0..churn.due(lines) {
+                let entry = self.rng.gen_range(0, self.pool.len());
+                let old = &mut self.pool[entry].0;
+                if let Some(name) = churn.replace(&mut self.rng, &mut self.namer, old) {
+                    *old = name;
+                    for sampler in &mut self.samplers {
+                        sampler.reset(entry);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> StatsdConfig {
+        StatsdConfig {
+            pool_size: 100,
+            sample_rates: "c=0.5".parse().unwrap(),
+            dogstatsd: Some(DogStatsd {
+                tags: 2,
+                events: 5,
+                service_checks: 5,
+                ..DogStatsd::default()
+            }),
+            churn: Some("1/10".parse().unwrap()),
+            ..StatsdConfig::default()
+        }
+    }
+
+    fn packets(seed: u64, config: &StatsdConfig) -> Vec<String> {
+        let mut gen = StatsdGenerator::new(seed, config);
+        (0..500)
+            .map(|_| {
+                let mut buf = String::new();
+                gen.fill_packet(&mut buf);
+                buf
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_same_packets() {
+        let config = config();
+        assert_eq!(packets(42, &config), packets(42, &config));
+        assert_ne!(packets(42, &config), packets(43, &config));
+    }
+
+    #[test]
+    fn non_finite_values_stay_out_of_the_tally() {
+        let mut tally = Tally::default();
+        tally.record(MetricType::Counter, 2, 3.0, 0.5);
+        tally.record(MetricType::Counter, 4, f64::NAN, 0.5);
+        tally.record(MetricType::Counter, 1, f64::NEG_INFINITY, 1.0);
+        tally.record(MetricType::Timer, 3, f64::INFINITY, 1.0);
+        tally.record(MetricType::Counter, 1, f64::MAX, 1.0);
+        tally.record(MetricType::Counter, 1, f64::MAX, 1.0);
+        assert_eq!(tally.lines(MetricType::Counter), 3);
+        assert_eq!(tally.effective(MetricType::Counter), 12.0 + f64::MAX);
+        assert_eq!(tally.non_finite(MetricType::Counter), 6);
+        assert_eq!(tally.lines(MetricType::Timer), 0);
+        assert_eq!(tally.non_finite(MetricType::Timer), 3);
+
+        // nor do a generator's adversarial values poison its tally
+        let config = StatsdConfig {
+            adversarial: 50,
+            ..config()
+        };
+        let mut gen = StatsdGenerator::new(1, &config);
+        for _ in 0..200 {
+            gen.fill_packet(&mut String::new());
+        }
+        let tally = gen.tally();
+        for metric_type in MetricType::ALL.iter() {
+            assert!(tally.effective(*metric_type).is_finite());
+        }
+        assert!(tally.non_finite(MetricType::Counter) > 0);
+    }
+
+    #[test]
+    fn values_format_like_a_client() {
+        assert_eq!(format_value(42.0), "42");
+        assert_eq!(format_value(-3.0), "-3");
+        assert_eq!(format_value(1.5), "1.5");
+        assert_eq!(format_value(-0.0), "-0");
+        assert_eq!(format_value(0.0), "0");
+        assert_eq!(format_value(1e300), "1e300");
+        assert_eq!(format_value(5e-324), "5e-324");
+        assert_eq!(format_value(f64::NAN), "NaN");
+        assert_eq!(format_value(f64::NEG_INFINITY), "-inf");
+    }
+
+    #[test]
+    fn forks_are_reproducible() {
+        let config = config();
+        let gen = StatsdGenerator::new(7, &config);
+        let (mut a, mut b) = (gen.fork(1), gen.fork(1));
+        for _ in 0..100 {
+            let (mut x, mut y) = (String::new(), String::new());
+            a.fill_packet(&mut x);
+            b.fill_packet(&mut y);
+            assert_eq!(x, y);
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! Generation of point timestamps
+//!
+//! Servers bucket points into time windows by their timestamp, and late,
+//! early, shuffled or repeated timestamps are where that bucketing goes
+//! wrong. A `Timestamps` describes how far from the wall clock points are
+//! stamped and how much disorder is mixed in.
+
+use generator::now_ms;
+use profile::parse_duration;
+use rand::Rng;
+use std::str::FromStr;
+
+/// How points are timestamped
+///
+/// Written as comma separated terms, any of
+///
+/// * `now`, the wall clock
+/// * `lag:D` and `future:D`, shifted D into the past or the future
+/// * `jitter:D`, moved up to D either way
+/// * `disorder:P:D`, P percent of points moved up to D into the past, so
+///   they arrive out of order
+/// * `duplicate:P`, P percent of points stamped like the point before them
+///
+/// with durations like `500ms`, `30s` or `5m`, for instance
+/// `lag:2m,jitter:500ms,duplicate:5`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Timestamps {
+    offset_ms: i64,
+    jitter_ms: i64,
+    disorder: (u32, i64),
+    duplicate: u32,
+}
+
+impl FromStr for Timestamps {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Timestamps, String> {
+        let mut timestamps = Timestamps::default();
+        for term in s.split(',').map(|t| t.trim()).filter(|t| !t.is_empty()) {
+            let args: Vec<&str> = term.split(':').collect();
+            let ms = |arg: &str| parse_duration(arg).map(|secs| (secs * 1_000.0) as i64);
+            let percent = |arg: &str| {
+                arg.parse::<u32>()
+                    .ok()
+                    .filter(|p| *p <= 100)
+                    .ok_or_else(|| format!("'{}' in '{}' is not a percentage", arg, term))
+            };
+            match (args[0], args.len()) {
+                ("now", 1) => {}
+                ("lag", 2) => timestamps.offset_ms -= ms(args[1])?,
+                ("future", 2) => timestamps.offset_ms += ms(args[1])?,
+                ("jitter", 2) => timestamps.jitter_ms = ms(args[1])?,
+                ("disorder", 3) => timestamps.disorder = (percent(args[1])?, ms(args[2])?),
+                ("duplicate", 2) => timestamps.duplicate = percent(args[1])?,
+                _ => return Err(format!("unknown timestamp term '{}'", term)),
+            }
+        }
+        Ok(timestamps)
+    }
+}
+
+/// Stamps points following a `Timestamps`
+#[derive(Debug, Clone)]
+pub struct Clock {
+    timestamps: Timestamps,
+    last: Option<i64>,
+}
+
+impl Clock {
+    /// Create a clock for `timestamps`
+    pub fn new(timestamps: &Timestamps) -> Clock {
+        Clock {
+            timestamps: timestamps.clone(),
+            last: None,
+        }
+    }
+
+    /// The timestamp, in milliseconds since the epoch, of the next point
+    pub fn next<R: Rng>(&mut self, rng: &mut R) -> i64 {
+        let t = &self.timestamps;
+        if let Some(last) = self.last {
+            if t.duplicate > 0 && rng.gen_range(0, 100) < t.duplicate {
+                return last;
+            }
+        }
+        let mut stamp = now_ms() + t.offset_ms;
+        if t.jitter_ms > 0 {
+            stamp += rng.gen_range(-t.jitter_ms, t.jitter_ms + 1);
+        }
+        let (disorder, window) = t.disorder;
+        if disorder > 0 && rng.gen_range(0, 100) < disorder {
+            stamp -= rng.gen_range(0, window + 1);
+        }
+        self.last = Some(stamp);
+        stamp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use generator::seeded_rng;
+
+    // offsets of `draws` stamps from the wall clock, with the slack of the
+    // clock moving while they are drawn
+    fn draw(spec: &str, draws: usize) -> (Vec<i64>, i64) {
+        let mut clock = Clock::new(&spec.parse().unwrap());
+        let mut rng = seeded_rng(12);
+        let start = now_ms();
+        let offsets = (0..draws).map(|_| clock.next(&mut rng) - start).collect();
+        (offsets, now_ms() - start)
+    }
+
+    #[test]
+    fn terms_parse() {
+        let timestamps = "lag:2m, future:30s,jitter:500ms,disorder:10:1s,duplicate:5"
+            .parse::<Timestamps>()
+            .unwrap();
+        assert_eq!(
+            timestamps,
+            Timestamps {
+                offset_ms: -90_000,
+                jitter_ms: 500,
+                disorder: (10, 1_000),
+                duplicate: 5,
+            }
+        );
+        assert_eq!("now".parse(), Ok(Timestamps::default()));
+        assert!("lag".parse::<Timestamps>().is_err());
+        assert!("lag:soon".parse::<Timestamps>().is_err());
+        assert!("disorder:101:1s".parse::<Timestamps>().is_err());
+        assert!("duplicate:-1".parse::<Timestamps>().is_err());
+        assert!("rewind:1s".parse::<Timestamps>().is_err());
+    }
+
+    #[test]
+    fn lag_and_future_shift_the_clock() {
+        let (offsets, slack) = draw("lag:1m", 100);
+        assert!(offsets
+            .iter()
+            .all(|o| -60_000 <= *o && *o <= -60_000 + slack));
+        let (offsets, slack) = draw("future:5s", 100);
+        assert!(offsets.iter().all(|o| 5_000 <= *o && *o <= 5_000 + slack));
+    }
+
+    #[test]
+    fn jitter_spreads_both_ways() {
+        let (offsets, slack) = draw("jitter:1s", 10_000);
+        assert!(offsets.iter().all(|o| -1_000 <= *o && *o <= 1_000 + slack));
+        assert!(offsets.iter().any(|o| *o < -900));
+        assert!(offsets.iter().any(|o| *o > 900 + slack));
+        let mean = offsets.iter().sum::<i64>() as f64 / offsets.len() as f64;
+        assert!(mean.abs() < 50.0 + slack as f64, "{}", mean);
+    }
+
+    #[test]
+    fn disorder_moves_a_share_into_the_past() {
+        let (offsets, slack) = draw("future:1h,disorder:20:10s", 10_000);
+        let moved = offsets.iter().filter(|o| **o < 3_600_000).count();
+        assert!(1_800 < moved && moved < 2_200, "{}", moved);
+        assert!(offsets
+            .iter()
+            .all(|o| 3_590_000 <= *o && *o <= 3_600_000 + slack));
+    }
+
+    #[test]
+    fn duplicates_repeat_the_last_stamp() {
+        let (offsets, _) = draw("jitter:1h,duplicate:30", 10_000);
+        let repeated = offsets.windows(2).filter(|w| w[0] == w[1]).count();
+        assert!(2_800 < repeated && repeated < 3_200, "{}", repeated);
+    }
+}
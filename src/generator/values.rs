@@ -0,0 +1,274 @@
+//! Generation of sample values
+//!
+//! Each metric type draws its values from a distribution of its own, so that
+//! quantile summaries and histograms on the server see realistic shapes.
+//! Monotonic counters and random walks keep their state per pool entry, the
+//! way a real series would. A share of values can be swapped for adversarial
+//! ones: NaN, infinities, the extremes of `f64` and subnormals.
+
+use rand::distributions::{Exp, IndependentSample, LogNormal, Normal};
+use rand::Rng;
+use std::str::FromStr;
+
+/// A distribution of sample values
+///
+/// Written as one of
+///
+/// * `int:LOW..HIGH`, integers drawn uniformly from LOW to HIGH inclusive,
+///   HIGH below `i64::MAX`
+/// * `uniform:LOW..HIGH`, reals drawn uniformly from LOW up to HIGH
+/// * `normal:MEAN:SD` and `lognormal:MEAN:SD`, the latter of the log
+/// * `exp:LAMBDA`
+/// * `pareto:SCALE:SHAPE`
+/// * `monotonic:STEP`, a counter growing by up to STEP each value
+/// * `walk:START:STEP`, a walk from START moving by up to STEP each value
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Distribution {
+    Int(i64, i64),
+    Uniform(f64, f64),
+    Normal(f64, f64),
+    LogNormal(f64, f64),
+    Exp(f64),
+    Pareto(f64, f64),
+    Monotonic(f64),
+    Walk(f64, f64),
+}
+
+impl FromStr for Distribution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Distribution, String> {
+        let args: Vec<&str> = s.split(':').collect();
+        let number = |arg: &str| {
+            arg.parse::<f64>()
+                .ok()
+                .filter(|n| n.is_finite())
+                .ok_or_else(|| format!("'{}' in '{}' is not a number", arg, s))
+        };
+        let positive = |arg: &str| {
+            number(arg).and_then(|n| {
+                if n > 0.0 {
+                    Ok(n)
+                } else {
+                    Err(format!("'{}' in '{}' must be positive", arg, s))
+                }
+            })
+        };
+        let range = |arg: &str| {
+            let idx = arg
+                .find("..")
+                .ok_or_else(|| format!("'{}' in '{}' is not a range like '0..999'", arg, s))?;
+            let (low, high) = (number(&arg[..idx])?, number(&arg[idx + 2..])?);
+            if low > high {
+                return Err(format!("range '{}' in '{}' is empty", arg, s));
+            }
+            Ok((low, high))
+        };
+        match (args[0], args.len()) {
+            ("int", 2) => {
+                let (low, high) = range(args[1])?;
+                // HIGH + 1 bounds the draw, so must itself fit an i64
+                if low.fract() != 0.0 || high.fract() != 0.0 {
+                    return Err(format!("range '{}' in '{}' is not of integers", args[1], s));
+                }
+                if low < i64::MIN as f64 || high >= i64::MAX as f64 {
+                    return Err(format!(
+                        "range '{}' in '{}' does not fit an i64",
+                        args[1], s
+                    ));
+                }
+                Ok(Distribution::Int(low as i64, high as i64))
+            }
+            ("uniform", 2) => {
+                let (low, high) = range(args[1])?;
+                Ok(Distribution::Uniform(low, high))
+            }
+            ("normal", 3) => Ok(Distribution::Normal(number(args[1])?, positive(args[2])?)),
+            ("lognormal", 3) => Ok(Distribution::LogNormal(
+                number(args[1])?,
+                positive(args[2])?,
+            )),
+            ("exp", 2) => Ok(Distribution::Exp(positive(args[1])?)),
+            ("pareto", 3) => Ok(Distribution::Pareto(positive(args[1])?, positive(args[2])?)),
+            ("monotonic", 2) => Ok(Distribution::Monotonic(positive(args[1])?)),
+            ("walk", 3) => Ok(Distribution::Walk(number(args[1])?, positive(args[2])?)),
+            _ => Err(format!("unknown value distribution '{}'", s)),
+        }
+    }
+}
+
+/// Split `type=distribution,...` into its pairs
+pub fn parse_assignments(s: &str) -> Result<Vec<(&str, Distribution)>, String> {
+    let mut assignments = Vec::new();
+    for pair in s.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+        let idx = pair
+            .find('=')
+            .ok_or_else(|| format!("'{}' is not a 'type=distribution' pair", pair))?;
+        assignments.push((pair[..idx].trim(), pair[idx + 1..].trim().parse()?));
+    }
+    Ok(assignments)
+}
+
+// the values that break naive arithmetic and formatting
+const ADVERSARIAL: [f64; 9] = [
+    f64::NAN,
+    f64::INFINITY,
+    f64::NEG_INFINITY,
+    f64::MAX,
+    f64::MIN,
+    5e-324,
+    -0.0,
+    1e300,
+    -1e-300,
+];
+
+/// Draws values from a `Distribution` for each entry of a pool
+#[derive(Debug, Clone)]
+pub struct Sampler {
+    distribution: Distribution,
+    adversarial: u32,
+    // the last value of each entry, for monotonic counters and walks
+    state: Vec<Option<f64>>,
+}
+
+impl Sampler {
+    /// Create a sampler for a pool of `len` entries, replacing
+    /// `adversarial` percent of values with adversarial ones
+    pub fn new(distribution: Distribution, adversarial: u32, len: usize) -> Sampler {
+        let stateful = match distribution {
+            Distribution::Monotonic(_) | Distribution::Walk(_, _) => len,
+            _ => 0,
+        };
+        Sampler {
+            distribution,
+            adversarial,
+            state: vec![None; stateful],
+        }
+    }
+
+    /// Draw the next value of the pool entry `entry`
+    pub fn sample<R: Rng>(&mut self, rng: &mut R, entry: usize) -> f64 {
+        if self.adversarial > 0 && rng.gen_range(0, 100) < self.adversarial {
+            return *rng.choose(&ADVERSARIAL).unwrap();
+        }
+        match self.distribution {
+            Distribution::Int(low, high) => rng.gen_range(low, high + 1) as f64,
+            Distribution::Uniform(low, high) => low + rng.gen::<f64>() * (high - low),
+            Distribution::Normal(mean, sd) => Normal::new(mean, sd).ind_sample(rng),
+            Distribution::LogNormal(mean, sd) => LogNormal::new(mean, sd).ind_sample(rng),
+            Distribution::Exp(lambda) => Exp::new(lambda).ind_sample(rng),
+            Distribution::Pareto(scale, shape) => {
+                // inverse transform of a uniform draw in (0, 1]
+                let u = 1.0 - rng.gen::<f64>();
+                scale / u.powf(1.0 / shape)
+            }
+            Distribution::Monotonic(step) => {
+                let next = self.state[entry].unwrap_or(0.0) + rng.gen_range(0.0, step);
+                self.state[entry] = Some(next);
+                next
+            }
+            Distribution::Walk(start, step) => {
+                let next = self.state[entry].unwrap_or(start) + rng.gen_range(-step, step);
+                self.state[entry] = Some(next);
+                next
+            }
+        }
+    }
+
+    /// Forget the state of `entry`, as when churn gives it a new name
+    pub fn reset(&mut self, entry: usize) {
+        if let Some(state) = self.state.get_mut(entry) {
+            *state = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use generator::seeded_rng;
+
+    fn samples(spec: &str, adversarial: u32, count: usize) -> Vec<f64> {
+        let mut sampler = Sampler::new(spec.parse().unwrap(), adversarial, 2);
+        let mut rng = seeded_rng(1);
+        (0..count).map(|_| sampler.sample(&mut rng, 0)).collect()
+    }
+
+    fn mean(values: &[f64]) -> f64 {
+        values.iter().sum::<f64>() / values.len() as f64
+    }
+
+    #[test]
+    fn distributions_parse() {
+        assert_eq!("int:-5..5".parse(), Ok(Distribution::Int(-5, 5)));
+        assert_eq!("uniform:0..1".parse(), Ok(Distribution::Uniform(0.0, 1.0)));
+        assert_eq!("walk:100:5".parse(), Ok(Distribution::Walk(100.0, 5.0)));
+        assert!("int:5..1".parse::<Distribution>().is_err());
+        assert!("int:0.5..3".parse::<Distribution>().is_err());
+        assert!("int:0..9223372036854775807"
+            .parse::<Distribution>()
+            .is_err());
+        assert!("int:0..1e300".parse::<Distribution>().is_err());
+        assert!("normal:0:0".parse::<Distribution>().is_err());
+        assert!("exp:-1".parse::<Distribution>().is_err());
+        assert!("uniform:0..NaN".parse::<Distribution>().is_err());
+        assert!("gamma:1".parse::<Distribution>().is_err());
+    }
+
+    #[test]
+    fn ints_cover_their_inclusive_range() {
+        let values = samples("int:-2..2", 0, 1_000);
+        for n in -2..3 {
+            assert!(values.contains(&(n as f64)));
+        }
+        assert!(values
+            .iter()
+            .all(|v| (-2.0..=2.0).contains(v) && v.fract() == 0.0));
+        // the widest range that parses
+        let values = samples("int:-9223372036854775808..9223372036854774784", 0, 100);
+        assert!(values.iter().all(|v| v.is_finite()));
+    }
+
+    #[test]
+    fn continuous_distributions_have_their_shape() {
+        let values = samples("uniform:10..20", 0, 10_000);
+        assert!(values.iter().all(|v| (10.0..20.0).contains(v)));
+        assert!((mean(&values) - 15.0).abs() < 0.2);
+        assert!((mean(&samples("normal:50:2", 0, 10_000)) - 50.0).abs() < 0.1);
+        assert!((mean(&samples("exp:4", 0, 10_000)) - 0.25).abs() < 0.01);
+        assert!(samples("pareto:3:2", 0, 1_000).iter().all(|v| *v >= 3.0));
+        assert!(samples("lognormal:0:1", 0, 1_000).iter().all(|v| *v > 0.0));
+    }
+
+    #[test]
+    fn stateful_distributions_keep_state_per_entry() {
+        let mut sampler = Sampler::new("monotonic:5".parse().unwrap(), 0, 2);
+        let mut rng = seeded_rng(1);
+        let counts: Vec<f64> = (0..100).map(|_| sampler.sample(&mut rng, 0)).collect();
+        assert!(counts.windows(2).all(|w| w[1] >= w[0]));
+        // another entry starts from scratch, as does a reset one
+        assert!(sampler.sample(&mut rng, 1) < 5.0);
+        sampler.reset(0);
+        assert!(sampler.sample(&mut rng, 0) < 5.0);
+
+        let mut sampler = Sampler::new("walk:100:1".parse().unwrap(), 0, 1);
+        let walk: Vec<f64> = (0..100).map(|_| sampler.sample(&mut rng, 0)).collect();
+        assert!((walk[0] - 100.0).abs() < 1.0);
+        assert!(walk.windows(2).all(|w| (w[1] - w[0]).abs() < 1.0));
+    }
+
+    #[test]
+    fn adversarial_values_replace_a_share() {
+        let values = samples("int:0..10", 20, 10_000);
+        let odd = values
+            .iter()
+            .filter(|v| {
+                !(0.0..=10.0).contains(*v) || v.fract() != 0.0 || **v == 0.0 && v.is_sign_negative()
+            })
+            .count();
+        // 20% adversarial, of which the 9 values fall outside the range
+        assert!(odd > 1_500 && odd < 2_500, "{}", odd);
+        assert!(values.iter().any(|v| v.is_nan()));
+        assert!(samples("int:0..10", 0, 1_000).iter().all(|v| v.is_finite()));
+    }
+}
@@ -1,4 +1,18 @@
 extern crate byteorder;
+extern crate clap;
+#[macro_use]
+extern crate lazy_static;
+#[cfg(target_os = "linux")]
+extern crate libc;
 extern crate protobuf;
+extern crate rand;
 
+pub mod batch;
+pub mod generator;
+pub mod pacer;
+pub mod profile;
 pub mod protocols;
+pub mod scenario;
+pub mod search;
+pub mod sequence;
+pub mod transport;
@@ -0,0 +1,94 @@
+//! Open-loop pacing of emission
+//!
+//! A closed-loop emitter sends, notices it has sent 'enough' and then
+//! sleeps. That lets a slow server slow the emitter down, hiding exactly the
+//! overload we mean to measure. The `Pacer` instead lays every unit of work
+//! out on a fixed timeline from the moment it was created and reports how
+//! far behind that timeline the caller has fallen.
+
+use profile::Profile;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Waits below this are spun out rather than slept, sleep being too coarse.
+const SPIN_THRESHOLD: f64 = 0.001;
+
+/// Schedules units of work -- lines, packets, payloads -- along a `Profile`
+pub struct Pacer {
+    start: Instant,
+    due: f64,
+    profile: Profile,
+}
+
+impl Pacer {
+    /// Create a new Pacer for `rate` units per second, starting now
+    pub fn new(rate: f64) -> Pacer {
+        assert!(rate > 0.0, "rate must be positive");
+        Pacer::with_profile(Profile::constant(rate))
+    }
+
+    /// Create a new Pacer following `profile`, starting now
+    pub fn with_profile(profile: Profile) -> Pacer {
+        Pacer {
+            start: Instant::now(),
+            due: 0.0,
+            profile,
+        }
+    }
+
+    /// The rate the profile currently calls for, None once it is over
+    pub fn target_rate(&self) -> Option<f64> {
+        self.profile.rate_at(self.start.elapsed().as_secs_f64())
+    }
+
+    /// Whether claiming `units` of schedule now would have to wait
+    ///
+    /// Callers holding work back, batched sends say, hand it over before
+    /// waiting so that it goes out when it was due.
+    pub fn would_wait(&self, units: usize) -> bool {
+        match self.profile.advance(self.due, units as f64) {
+            Some(due) => due > self.start.elapsed().as_secs_f64(),
+            None => false,
+        }
+    }
+
+    /// Claim `units` of schedule and wait until they are due
+    ///
+    /// Returns how far behind schedule the caller was when it arrived, or
+    /// None if the profile has run out. A caller that is behind is not made
+    /// to wait, so it will burst until it has caught up.
+    pub fn pace(&mut self, units: usize) -> Option<Duration> {
+        self.due = self.profile.advance(self.due, units as f64)?;
+        let lag = loop {
+            let now = self.start.elapsed().as_secs_f64();
+            let wait = self.due - now;
+            if wait <= 0.0 {
+                break -wait;
+            } else if wait > SPIN_THRESHOLD {
+                thread::sleep(Duration::from_secs_f64(wait - SPIN_THRESHOLD));
+            } else {
+                thread::yield_now();
+            }
+        };
+        Some(Duration::from_secs_f64(lag))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_only_ahead_of_schedule() {
+        // the first unit at 1/s is due a second from now
+        let pacer = Pacer::new(1.0);
+        assert!(pacer.would_wait(1));
+        // and at 1G/s a nanosecond from now, which has passed
+        let pacer = Pacer::new(1e9);
+        thread::sleep(Duration::from_millis(1));
+        assert!(!pacer.would_wait(1));
+        // an ended profile has nothing to wait for
+        let pacer = Pacer::with_profile(Profile::constant_for(10.0, 0.0));
+        assert!(!pacer.would_wait(1));
+    }
+}
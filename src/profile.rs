@@ -0,0 +1,326 @@
+//! Multi-phase load profiles
+//!
+//! A profile describes a run as a sequence of phases, each of which either
+//! holds a rate or ramps linearly between two rates. Profiles are written as
+//! comma separated phases, each a rate or a `from..to` rate range followed by
+//! `@` and the duration of the phase:
+//!
+//! ```text
+//! 10k@30s,10k..1M@5m,1M@5m,3M@10s,1M..0@1m
+//! ```
+//!
+//! Rates accept `k` and `M` suffixes. Durations accept `ms`, `s`, `m` and `h`
+//! suffixes and are otherwise taken to be seconds.
+
+use std::f64;
+use std::str::FromStr;
+
+/// One stage of a load profile
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Phase {
+    /// Rate at the start of the phase, units per second
+    pub from: f64,
+    /// Rate at the end of the phase, units per second
+    pub to: f64,
+    /// Length of the phase in seconds
+    pub duration: f64,
+}
+
+impl Phase {
+    fn rate_at(&self, offset: f64) -> f64 {
+        if self.from == self.to {
+            self.from
+        } else {
+            self.from + (self.to - self.from) * (offset / self.duration)
+        }
+    }
+
+    /// Units emitted between `offset` and the end of the phase
+    fn remaining(&self, offset: f64) -> f64 {
+        if self.duration.is_infinite() {
+            return if self.from > 0.0 { f64::INFINITY } else { 0.0 };
+        }
+        let end = self.to;
+        let here = self.rate_at(offset);
+        (here + end) / 2.0 * (self.duration - offset)
+    }
+
+    /// Offset at which `units` will have been emitted, starting from
+    /// `offset`. Caller guarantees `units` fits in the phase.
+    fn advance(&self, offset: f64, units: f64) -> f64 {
+        let slope = if self.duration.is_infinite() {
+            0.0
+        } else {
+            (self.to - self.from) / self.duration
+        };
+        let here = self.rate_at(offset);
+        if slope.abs() < f64::EPSILON {
+            offset + units / here
+        } else {
+            // solve here*dt + slope/2*dt^2 = units for dt
+            let disc = (here * here + 2.0 * slope * units).max(0.0);
+            offset + (disc.sqrt() - here) / slope
+        }
+    }
+}
+
+/// A sequence of phases
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    phases: Vec<Phase>,
+}
+
+impl Profile {
+    /// A profile holding `rate` forever
+    pub fn constant(rate: f64) -> Profile {
+        Profile {
+            phases: vec![
+                Phase {
+                    from: rate,
+                    to: rate,
+                    duration: f64::INFINITY,
+                },
+            ],
+        }
+    }
+
+    /// A profile holding `rate` for `duration` seconds
+    pub fn constant_for(rate: f64, duration: f64) -> Profile {
+        Profile {
+            phases: vec![
+                Phase {
+                    from: rate,
+                    to: rate,
+                    duration,
+                },
+            ],
+        }
+    }
+
+    /// This profile with every rate multiplied by `factor`
+    pub fn scaled(&self, factor: f64) -> Profile {
+        Profile {
+            phases: self.phases
+                .iter()
+                .map(|p| Phase {
+                    from: p.from * factor,
+                    to: p.to * factor,
+                    duration: p.duration,
+                })
+                .collect(),
+        }
+    }
+
+    /// The phases making up the profile
+    pub fn phases(&self) -> &[Phase] {
+        &self.phases
+    }
+
+    /// Total length of the profile in seconds
+    pub fn duration(&self) -> f64 {
+        self.phases.iter().map(|p| p.duration).sum()
+    }
+
+    /// Target rate `elapsed` seconds into the profile, None once it is over
+    pub fn rate_at(&self, elapsed: f64) -> Option<f64> {
+        let mut start = 0.0;
+        for phase in &self.phases {
+            if elapsed < start + phase.duration {
+                return Some(phase.rate_at(elapsed - start));
+            }
+            start += phase.duration;
+        }
+        None
+    }
+
+    /// The time, in seconds from the start of the profile, at which `units`
+    /// more units will have been emitted if emission begins at `elapsed`
+    ///
+    /// Returns None if the profile ends before that many units are due.
+    pub fn advance(&self, elapsed: f64, units: f64) -> Option<f64> {
+        let mut start = 0.0;
+        let mut units = units;
+        for phase in &self.phases {
+            let end = start + phase.duration;
+            if elapsed < end {
+                let offset = (elapsed - start).max(0.0);
+                let remaining = phase.remaining(offset);
+                if units <= remaining && remaining > 0.0 {
+                    return Some(start + phase.advance(offset, units));
+                }
+                units -= remaining;
+            }
+            start = end;
+        }
+        None
+    }
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Profile, String> {
+        let mut phases = Vec::new();
+        for spec in s.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+            let mut parts = spec.splitn(2, '@');
+            let rates = parts.next().unwrap();
+            let duration = match parts.next() {
+                Some(d) => parse_duration(d)?,
+                None => return Err(format!("phase '{}' is missing '@duration'", spec)),
+            };
+            let (from, to) = match rates.find("..") {
+                Some(idx) => (parse_rate(&rates[..idx])?, parse_rate(&rates[idx + 2..])?),
+                None => {
+                    let rate = parse_rate(rates)?;
+                    (rate, rate)
+                }
+            };
+            phases.push(Phase { from, to, duration });
+        }
+        if phases.is_empty() {
+            return Err("profile has no phases".into());
+        }
+        Ok(Profile { phases })
+    }
+}
+
+/// Parse an increasing `low..high` pair of rates
+pub fn parse_bounds(s: &str) -> Result<(f64, f64), String> {
+    match s.find("..") {
+        Some(idx) => {
+            let low = parse_rate(&s[..idx])?;
+            let high = parse_rate(&s[idx + 2..])?;
+            if low < high {
+                Ok((low, high))
+            } else {
+                Err(format!("'{}' is not an increasing range", s))
+            }
+        }
+        None => Err(format!("'{}' is not a 'low..high' range", s)),
+    }
+}
+
+/// Parse a rate in units per second, allowing `k` and `M` suffixes
+pub fn parse_rate(s: &str) -> Result<f64, String> {
+    let s = s.trim();
+    let (num, scale) = if let Some(n) = s.strip_suffix('k') {
+        (n, 1_000.0)
+    } else if let Some(n) = s.strip_suffix('M') {
+        (n, 1_000_000.0)
+    } else {
+        (s, 1.0)
+    };
+    match num.parse::<f64>() {
+        Ok(r) if r >= 0.0 && r.is_finite() => Ok(r * scale),
+        _ => Err(format!("'{}' is not a valid rate", s)),
+    }
+}
+
+/// Parse a rate like `parse_rate`, refusing 0
+pub fn parse_positive_rate(s: &str) -> Result<f64, String> {
+    match parse_rate(s)? {
+        r if r > 0.0 => Ok(r),
+        _ => Err(format!("'{}' is not a positive rate", s.trim())),
+    }
+}
+
+/// Parse a duration into seconds, allowing `ms`, `s`, `m` and `h` suffixes
+pub fn parse_duration(s: &str) -> Result<f64, String> {
+    let s = s.trim();
+    let (num, scale) = if let Some(n) = s.strip_suffix("ms") {
+        (n, 0.001)
+    } else if let Some(n) = s.strip_suffix('s') {
+        (n, 1.0)
+    } else if let Some(n) = s.strip_suffix('m') {
+        (n, 60.0)
+    } else if let Some(n) = s.strip_suffix('h') {
+        (n, 3_600.0)
+    } else {
+        (s, 1.0)
+    };
+    match num.parse::<f64>() {
+        Ok(d) if d > 0.0 && d.is_finite() => Ok(d * scale),
+        _ => Err(format!("'{}' is not a valid duration", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn phase_advance_holds_and_ramps() {
+        let hold = Phase {
+            from: 10.0,
+            to: 10.0,
+            duration: 2.0,
+        };
+        assert!(close(hold.advance(0.0, 10.0), 1.0));
+        assert!(close(hold.advance(0.5, 5.0), 1.0));
+
+        // 2.5 t^2 units by t
+        let up = Phase {
+            from: 0.0,
+            to: 10.0,
+            duration: 2.0,
+        };
+        assert!(close(up.advance(0.0, 2.5), 1.0));
+        assert!(close(up.advance(1.0, 7.5), 2.0));
+
+        let down = Phase {
+            from: 10.0,
+            to: 0.0,
+            duration: 1.0,
+        };
+        assert!(close(down.remaining(0.0), 5.0));
+        assert!(close(down.advance(0.0, 5.0), 1.0));
+        assert!(close(down.advance(0.0, 3.75), 0.5));
+    }
+
+    #[test]
+    fn profile_advance_spans_phases() {
+        let profile = "10@1s,20@1s".parse::<Profile>().unwrap();
+        assert!(close(profile.advance(0.5, 10.0).unwrap(), 1.25));
+        assert!(close(profile.advance(0.0, 30.0).unwrap(), 2.0));
+        assert_eq!(profile.advance(0.0, 30.5), None);
+        assert_eq!(profile.advance(2.0, 1.0), None);
+    }
+
+    #[test]
+    fn profile_advance_skips_zero_rate_phases() {
+        let profile = "10@1s,0@1s,10@1s".parse::<Profile>().unwrap();
+        assert!(close(profile.advance(0.0, 15.0).unwrap(), 2.5));
+        assert!(close(profile.advance(1.5, 5.0).unwrap(), 2.5));
+
+        let idle = "0@1s".parse::<Profile>().unwrap();
+        assert_eq!(idle.advance(0.0, 1.0), None);
+    }
+
+    #[test]
+    fn profile_advance_through_ramps() {
+        let profile = "0..10@2s,10..0@1s".parse::<Profile>().unwrap();
+        assert!(close(profile.advance(0.0, 10.0).unwrap(), 2.0));
+        assert!(close(profile.advance(0.0, 15.0).unwrap(), 3.0));
+        assert_eq!(profile.advance(0.0, 15.5), None);
+    }
+
+    #[test]
+    fn constant_profiles_never_end() {
+        let profile = Profile::constant(100.0);
+        assert!(close(profile.advance(1e6, 50.0).unwrap(), 1e6 + 0.5));
+    }
+
+    #[test]
+    fn rates_parse() {
+        assert_eq!(parse_rate("10k"), Ok(10_000.0));
+        assert_eq!(parse_rate("0"), Ok(0.0));
+        assert!(parse_rate("-1").is_err());
+        assert_eq!(parse_positive_rate("2M"), Ok(2_000_000.0));
+        assert!(parse_positive_rate("0").is_err());
+        assert!(parse_positive_rate("0k").is_err());
+    }
+}
@@ -0,0 +1,462 @@
+//! Framing of native payloads
+//!
+//! Every tool speaking the native protocol reads and writes frames through
+//! this module: `write_frame` and `read_frame` for blocking streams, and a
+//! `Decoder` for callers that receive bytes in arbitrary chunks.
+
+use super::Payload;
+use byteorder::{BigEndian, ByteOrder};
+use protobuf::{self, Message, ProtobufError};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+/// Length of the size prefix of a frame
+pub const PREFIX_LEN: usize = 4;
+
+/// A frame size no sane payload comes near, for callers without an opinion
+pub const DEFAULT_MAX_FRAME: usize = 64 * 1024 * 1024;
+
+/// Why a frame could not be read or written
+#[derive(Debug)]
+pub enum FrameError {
+    /// The stream ended `read` bytes into a frame part `expected` bytes long
+    ShortRead { expected: usize, read: usize },
+    /// A frame claimed `size` bytes, more than the `max` allowed
+    Oversized { size: usize, max: usize },
+    /// A frame's body is not a valid payload
+    Protobuf(ProtobufError),
+    /// The underlying stream failed
+    Io(io::Error),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FrameError::ShortRead { expected, read } => write!(
+                f,
+                "stream ended {} bytes into a {} byte read",
+                read, expected
+            ),
+            FrameError::Oversized { size, max } => {
+                write!(f, "frame of {} bytes exceeds the maximum of {}", size, max)
+            }
+            FrameError::Protobuf(ref e) => write!(f, "invalid payload: {}", e),
+            FrameError::Io(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for FrameError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            FrameError::Protobuf(ref e) => Some(e),
+            FrameError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for FrameError {
+    fn from(e: io::Error) -> FrameError {
+        FrameError::Io(e)
+    }
+}
+
+impl From<ProtobufError> for FrameError {
+    fn from(e: ProtobufError) -> FrameError {
+        FrameError::Protobuf(e)
+    }
+}
+
+/// Encode `payload` as a frame, size prefix included
+pub fn encode_frame(payload: &Payload) -> Result<Vec<u8>, FrameError> {
+    let mut frame = vec![0; PREFIX_LEN];
+    payload.write_to_vec(&mut frame)?;
+    let size = frame.len() - PREFIX_LEN;
+    if size > u32::MAX as usize {
+        return Err(FrameError::Oversized {
+            size,
+            max: u32::MAX as usize,
+        });
+    }
+    BigEndian::write_u32(&mut frame[..PREFIX_LEN], size as u32);
+    Ok(frame)
+}
+
+/// Write `payload` to `writer` as one frame, returning the number of bytes
+/// written
+pub fn write_frame<W: Write>(writer: &mut W, payload: &Payload) -> Result<usize, FrameError> {
+    let frame = encode_frame(payload)?;
+    writer.write_all(&frame)?;
+    writer.flush()?;
+    Ok(frame.len())
+}
+
+/// Read one frame from `reader`, refusing frames larger than `max_frame`
+///
+/// Returns None if the stream ended cleanly between frames.
+pub fn read_frame<R: Read>(
+    reader: &mut R,
+    max_frame: usize,
+) -> Result<Option<Payload>, FrameError> {
+    let mut prefix = [0; PREFIX_LEN];
+    match read_full(reader, &mut prefix)? {
+        0 => return Ok(None),
+        PREFIX_LEN => {}
+        read => {
+            return Err(FrameError::ShortRead {
+                expected: PREFIX_LEN,
+                read,
+            })
+        }
+    }
+    let size = BigEndian::read_u32(&prefix) as usize;
+    if size > max_frame {
+        return Err(FrameError::Oversized {
+            size,
+            max: max_frame,
+        });
+    }
+    let mut body = vec![0; size];
+    let read = read_full(reader, &mut body)?;
+    if read < size {
+        return Err(FrameError::ShortRead {
+            expected: size,
+            read,
+        });
+    }
+    Ok(Some(protobuf::parse_from_bytes::<Payload>(&body)?))
+}
+
+/// Read until `buf` is full or the stream ends, returning the bytes read
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+/// Decodes frames from bytes arriving in arbitrary chunks
+///
+/// A frame that cannot be decoded stays buffered until the caller decides
+/// how to get past it: `skip_frame` trusts its size prefix and drops it
+/// whole, `skip_byte` drops a single byte so that decoding can hunt for the
+/// next frame boundary in a corrupted stream.
+pub struct Decoder {
+    buf: Vec<u8>,
+    // bytes before this are decoded or skipped, awaiting compaction
+    start: usize,
+    max_frame: usize,
+    // bytes of a skipped frame yet to arrive
+    skipping: usize,
+}
+
+impl Decoder {
+    /// Create a decoder refusing frames larger than `max_frame`
+    pub fn new(max_frame: usize) -> Decoder {
+        Decoder {
+            buf: Vec::new(),
+            start: 0,
+            max_frame,
+            skipping: 0,
+        }
+    }
+
+    /// Buffer `bytes` for decoding
+    pub fn feed(&mut self, bytes: &[u8]) {
+        let skipped = self.skipping.min(bytes.len());
+        self.skipping -= skipped;
+        if self.start > 0 {
+            self.buf.drain(..self.start);
+            self.start = 0;
+        }
+        self.buf.extend_from_slice(&bytes[skipped..]);
+    }
+
+    /// Buffer whatever one read from `reader` returns, returning the number
+    /// of bytes read, 0 at the end of the stream
+    pub fn read_from<R: Read>(&mut self, reader: &mut R) -> io::Result<usize> {
+        let mut chunk = [0; 64 * 1024];
+        let read = reader.read(&mut chunk)?;
+        self.feed(&chunk[..read]);
+        Ok(read)
+    }
+
+    /// Number of bytes buffered but not yet decoded
+    pub fn buffered(&self) -> usize {
+        self.buf.len() - self.start
+    }
+
+    fn pending(&self) -> &[u8] {
+        &self.buf[self.start..]
+    }
+
+    /// The size claimed by the frame at the head of the buffer
+    fn frame_size(&self) -> Option<usize> {
+        let pending = self.pending();
+        if pending.len() < PREFIX_LEN {
+            None
+        } else {
+            Some(BigEndian::read_u32(&pending[..PREFIX_LEN]) as usize)
+        }
+    }
+
+    /// Decode the next frame, if it has been buffered in full
+    ///
+    /// On error the offending frame is left in the buffer.
+    pub fn decode(&mut self) -> Result<Option<Payload>, FrameError> {
+        let size = match self.frame_size() {
+            Some(size) => size,
+            None => return Ok(None),
+        };
+        if size > self.max_frame {
+            return Err(FrameError::Oversized {
+                size,
+                max: self.max_frame,
+            });
+        }
+        let end = PREFIX_LEN + size;
+        if self.buffered() < end {
+            return Ok(None);
+        }
+        let payload = protobuf::parse_from_bytes::<Payload>(&self.pending()[PREFIX_LEN..end])?;
+        self.start += end;
+        Ok(Some(payload))
+    }
+
+    /// Drop the frame at the head of the buffer, including the part of it
+    /// yet to arrive
+    pub fn skip_frame(&mut self) {
+        let end = match self.frame_size() {
+            Some(size) => PREFIX_LEN + size,
+            None => return,
+        };
+        if end <= self.buffered() {
+            self.start += end;
+        } else {
+            self.skipping = end - self.buffered();
+            self.buf.clear();
+            self.start = 0;
+        }
+    }
+
+    /// Drop the byte at the head of the buffer
+    pub fn skip_byte(&mut self) {
+        if self.buffered() > 0 {
+            self.start += 1;
+        }
+    }
+
+    /// Check, once the stream has ended, that no partial frame was left
+    pub fn finish(&self) -> Result<(), FrameError> {
+        if self.skipping > 0 {
+            return Err(FrameError::ShortRead {
+                expected: self.skipping,
+                read: 0,
+            });
+        }
+        if self.buffered() == 0 {
+            return Ok(());
+        }
+        let expected = match self.frame_size() {
+            Some(size) => PREFIX_LEN + size,
+            None => PREFIX_LEN,
+        };
+        Err(FrameError::ShortRead {
+            expected,
+            read: self.buffered(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocols::native::Telemetry;
+    use std::io::Cursor;
+
+    fn payload(name: &str, samples: usize) -> Payload {
+        let mut point = Telemetry::new();
+        point.set_name(name.to_string());
+        point.set_samples(vec![1.5; samples]);
+        let mut payload = Payload::new();
+        payload.mut_points().push(point);
+        payload
+    }
+
+    fn frame(name: &str, samples: usize) -> Vec<u8> {
+        encode_frame(&payload(name, samples)).unwrap()
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let mut stream = Vec::new();
+        let written = write_frame(&mut stream, &payload("a", 3)).unwrap();
+        write_frame(&mut stream, &payload("b", 20)).unwrap();
+        assert_eq!(written, frame("a", 3).len());
+
+        let mut reader = Cursor::new(&stream);
+        let first = read_frame(&mut reader, DEFAULT_MAX_FRAME).unwrap();
+        assert_eq!(first, Some(payload("a", 3)));
+        let second = read_frame(&mut reader, DEFAULT_MAX_FRAME).unwrap();
+        assert_eq!(second, Some(payload("b", 20)));
+        assert_eq!(read_frame(&mut reader, DEFAULT_MAX_FRAME).unwrap(), None);
+
+        // and a byte at a time through a decoder
+        let mut decoder = Decoder::new(DEFAULT_MAX_FRAME);
+        let mut decoded = Vec::new();
+        for byte in &stream {
+            decoder.feed(&[*byte]);
+            while let Some(payload) = decoder.decode().unwrap() {
+                decoded.push(payload);
+            }
+        }
+        assert_eq!(decoded, vec![payload("a", 3), payload("b", 20)]);
+        assert!(decoder.finish().is_ok());
+    }
+
+    #[test]
+    fn short_prefix() {
+        match read_frame(&mut Cursor::new(&[0, 0]), DEFAULT_MAX_FRAME) {
+            Err(FrameError::ShortRead { expected, read }) => {
+                assert_eq!((expected, read), (PREFIX_LEN, 2))
+            }
+            other => panic!("expected a short read, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn short_body() {
+        let mut stream = frame("a", 3);
+        let size = stream.len() - PREFIX_LEN;
+        stream.pop();
+        match read_frame(&mut Cursor::new(&stream), DEFAULT_MAX_FRAME) {
+            Err(FrameError::ShortRead { expected, read }) => {
+                assert_eq!((expected, read), (size, size - 1))
+            }
+            other => panic!("expected a short read, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn oversized_frames_are_refused() {
+        let stream = frame("a", 3);
+        let size = stream.len() - PREFIX_LEN;
+        match read_frame(&mut Cursor::new(&stream), size - 1) {
+            Err(FrameError::Oversized { size: s, max }) => assert_eq!((s, max), (size, size - 1)),
+            other => panic!("expected an oversized frame, got {:?}", other),
+        }
+        assert!(read_frame(&mut Cursor::new(&stream), size)
+            .unwrap()
+            .is_some());
+
+        let mut decoder = Decoder::new(size - 1);
+        decoder.feed(&stream[..PREFIX_LEN]);
+        match decoder.decode() {
+            Err(FrameError::Oversized { size: s, .. }) => assert_eq!(s, size),
+            other => panic!("expected an oversized frame, got {:?}", other),
+        }
+        // the frame is left buffered
+        assert_eq!(decoder.buffered(), PREFIX_LEN);
+    }
+
+    #[test]
+    fn skip_frame_drops_a_buffered_frame() {
+        let mut stream = frame("bad", 3);
+        // a body that is not a payload
+        for byte in &mut stream[PREFIX_LEN..] {
+            *byte = 0xFF;
+        }
+        stream.extend(frame("good", 1));
+        let mut decoder = Decoder::new(DEFAULT_MAX_FRAME);
+        decoder.feed(&stream);
+        match decoder.decode() {
+            Err(FrameError::Protobuf(_)) => {}
+            other => panic!("expected an invalid payload, got {:?}", other),
+        }
+        decoder.skip_frame();
+        assert_eq!(decoder.decode().unwrap(), Some(payload("good", 1)));
+        assert_eq!(decoder.buffered(), 0);
+    }
+
+    #[test]
+    fn skip_frame_carries_over_feeds() {
+        let big = frame("big", 100);
+        let mut decoder = Decoder::new(16);
+        decoder.feed(&big[..10]);
+        assert!(decoder.decode().is_err());
+        decoder.skip_frame();
+        assert_eq!(decoder.buffered(), 0);
+
+        // the rest of the skipped frame arrives over several feeds, the
+        // last one carrying the start of the next frame
+        let rest = &big[10..];
+        let (middle, tail) = rest.split_at(rest.len() - 5);
+        decoder.feed(&middle[..20]);
+        decoder.feed(&middle[20..]);
+        assert_eq!(decoder.buffered(), 0);
+        let mut chunk = tail.to_vec();
+        chunk.extend(frame("a", 1));
+        decoder.feed(&chunk);
+        assert_eq!(decoder.decode().unwrap(), Some(payload("a", 1)));
+        assert!(decoder.finish().is_ok());
+    }
+
+    #[test]
+    fn skip_byte_resyncs() {
+        let mut decoder = Decoder::new(1024);
+        // a stray byte makes the prefix claim far more than the maximum
+        decoder.feed(&[0xFF]);
+        decoder.feed(&frame("a", 2));
+        assert!(decoder.decode().is_err());
+        decoder.skip_byte();
+        assert_eq!(decoder.decode().unwrap(), Some(payload("a", 2)));
+
+        // skipping an empty buffer does nothing
+        decoder.skip_byte();
+        assert_eq!(decoder.buffered(), 0);
+    }
+
+    #[test]
+    fn finish_reports_partial_frames() {
+        let stream = frame("a", 3);
+
+        let mut decoder = Decoder::new(DEFAULT_MAX_FRAME);
+        assert!(decoder.finish().is_ok());
+        decoder.feed(&stream[..2]);
+        match decoder.finish() {
+            Err(FrameError::ShortRead { expected, read }) => {
+                assert_eq!((expected, read), (PREFIX_LEN, 2))
+            }
+            other => panic!("expected a short read, got {:?}", other),
+        }
+
+        let mut decoder = Decoder::new(DEFAULT_MAX_FRAME);
+        decoder.feed(&stream[..stream.len() - 1]);
+        assert_eq!(decoder.decode().unwrap(), None);
+        match decoder.finish() {
+            Err(FrameError::ShortRead { expected, read }) => {
+                assert_eq!((expected, read), (stream.len(), stream.len() - 1))
+            }
+            other => panic!("expected a short read, got {:?}", other),
+        }
+
+        // a skipped frame cut short is reported too
+        let mut decoder = Decoder::new(4);
+        decoder.feed(&stream[..PREFIX_LEN + 1]);
+        decoder.skip_frame();
+        match decoder.finish() {
+            Err(FrameError::ShortRead { expected, read }) => {
+                assert_eq!((expected, read), (stream.len() - PREFIX_LEN - 1, 0))
+            }
+            other => panic!("expected a short read, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,33 @@
+//! The cernan native protocol
+//!
+//! Payloads are protobuf messages, generated into `proto`, sent over a stream
+//! in frames of a big-endian u32 length followed by that many bytes of
+//! message. The `codec` module reads and writes those frames.
+
+#[allow(clippy::all, renamed_and_removed_lints, bare_trait_objects, static_mut_refs,
+        mismatched_lifetime_syntaxes)]
+mod proto;
+
+pub mod codec;
+
+pub use self::proto::*;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protobuf::Message;
+
+    // guards the hand patch to `Telemetry::compute_size` against
+    // regeneration: with 16 or more samples the packed length no longer
+    // fits the one byte varint the generated code assumed
+    #[test]
+    fn telemetry_size_matches_encoding() {
+        for samples in &[0, 1, 15, 16, 17, 1_000] {
+            let mut point = Telemetry::new();
+            point.set_name("a".to_string());
+            point.set_samples(vec![1.0; *samples]);
+            let bytes = point.write_to_bytes().unwrap();
+            assert_eq!(point.compute_size() as usize, bytes.len());
+        }
+    }
+}
@@ -1,5 +1,8 @@
 // This file is generated. Do not edit
 // @generated
+//
+// Except for lines marked PATCHED BY HAND, which must be carried over
+// whenever this file is regenerated.
 
 // https://github.com/Manishearth/rust-clippy/issues/702
 #![allow(unknown_lints)]
@@ -499,7 +502,8 @@ impl ::protobuf::Message for LogLine {
         if let Some(ref v) = self.value.as_ref() {
             os.write_string(2, &v)?;
         }
-        ::protobuf::rt::write_map_with_cached_sizes::<::protobuf::types::ProtobufTypeString, ::protobuf::types::ProtobufTypeString>(3, &self.metadata, os)?;
+        // PATCHED BY HAND: in key order, see write_sorted_map
+        write_sorted_map(3, &self.metadata, os)?;
         if let Some(v) = self.timestamp_ms {
             os.write_int64(4, v)?;
         }
@@ -915,7 +919,9 @@ impl ::protobuf::Message for Telemetry {
             my_size += ::protobuf::rt::string_size(1, &v);
         }
         if !self.samples.is_empty() {
-            my_size += 1 + ::protobuf::rt::compute_raw_varint32_size(self.samples.len() as u32) + (self.samples.len() * 8) as u32;
+            // PATCHED BY HAND: the packed length prefix holds the byte
+            // length of the samples, not their count, as generated
+            my_size += 1 + ::protobuf::rt::compute_raw_varint32_size((self.samples.len() * 8) as u32) + (self.samples.len() * 8) as u32;
         }
         if let Some(v) = self.persisted {
             my_size += 2;
@@ -951,7 +957,8 @@ impl ::protobuf::Message for Telemetry {
         if let Some(v) = self.method {
             os.write_enum(4, v.value())?;
         }
-        ::protobuf::rt::write_map_with_cached_sizes::<::protobuf::types::ProtobufTypeString, ::protobuf::types::ProtobufTypeString>(5, &self.metadata, os)?;
+        // PATCHED BY HAND: in key order, see write_sorted_map
+        write_sorted_map(5, &self.metadata, os)?;
         if let Some(v) = self.timestamp_ms {
             os.write_int64(6, v)?;
         }
@@ -1313,3 +1320,18 @@ pub fn file_descriptor_proto() -> &'static ::protobuf::descriptor::FileDescripto
         })
     }
 }
+
+// PATCHED BY HAND: maps are written in key order rather than the HashMap's,
+// which changes from run to run, so a seed always gives the same bytes
+fn write_sorted_map(field_number: u32, map: &::std::collections::HashMap<::std::string::String, ::std::string::String>, os: &mut ::protobuf::CodedOutputStream) -> ::protobuf::ProtobufResult<()> {
+    let mut entries: ::std::vec::Vec<_> = map.iter().collect();
+    entries.sort();
+    for (k, v) in entries {
+        let entry_len = 1 + ::protobuf::rt::string_size_no_tag(k) + 1 + ::protobuf::rt::string_size_no_tag(v);
+        os.write_tag(field_number, ::protobuf::wire_format::WireTypeLengthDelimited)?;
+        os.write_raw_varint32(entry_len)?;
+        os.write_string(1, k)?;
+        os.write_string(2, v)?;
+    }
+    ::std::result::Result::Ok(())
+}
@@ -0,0 +1,373 @@
+//! Parsing of statsd lines
+//!
+//! Accepts plain statsd metric lines, with or without a sample rate, and the
+//! DogStatsD extensions the statsd generator can produce: tags, container
+//! ids, timestamps, events and service checks. Parsing is strict, so that a
+//! listener counts anything a lenient server might quietly drop as an error.
+
+use generator::statsd::MetricType;
+
+/// One parsed statsd line
+#[derive(Debug, Clone, PartialEq)]
+pub enum Line<'a> {
+    Metric(Metric<'a>),
+    Event(Event<'a>),
+    ServiceCheck(ServiceCheck<'a>),
+}
+
+/// A metric line, `name:value|type` and its optional fields
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metric<'a> {
+    pub name: &'a str,
+    /// The values, more than one when packed as `name:1:2:3|type`
+    pub values: Vec<&'a str>,
+    pub metric_type: MetricType,
+    pub sample_rate: Option<f64>,
+    pub tags: Vec<&'a str>,
+    pub container_id: Option<&'a str>,
+    pub timestamp: Option<u64>,
+}
+
+impl<'a> Metric<'a> {
+    /// The sample rate, 1 when none was given
+    pub fn rate(&self) -> f64 {
+        self.sample_rate.unwrap_or(1.0)
+    }
+
+    /// The sum of the line's values, set members counting as 1
+    pub fn total(&self) -> f64 {
+        match self.metric_type {
+            MetricType::Set => self.values.len() as f64,
+            // values of other types were checked to be numbers when parsed
+            _ => self.values.iter().map(|v| v.parse::<f64>().unwrap()).sum(),
+        }
+    }
+}
+
+/// A DogStatsD event, `_e{title_len,text_len}:title|text` and its fields
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event<'a> {
+    pub title: &'a str,
+    pub text: &'a str,
+    pub tags: Vec<&'a str>,
+}
+
+/// A DogStatsD service check, `_sc|name|status` and its fields
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServiceCheck<'a> {
+    pub name: &'a str,
+    pub status: u8,
+    pub tags: Vec<&'a str>,
+}
+
+/// Parse a single line, without its trailing newline
+pub fn parse_line(line: &str) -> Result<Line<'_>, String> {
+    if line.starts_with("_e{") {
+        parse_event(line).map(Line::Event)
+    } else if line.starts_with("_sc|") {
+        parse_service_check(line).map(Line::ServiceCheck)
+    } else {
+        parse_metric(line).map(Line::Metric)
+    }
+}
+
+fn parse_metric(line: &str) -> Result<Metric<'_>, String> {
+    let mut fields = line.split('|');
+    let head = fields.next().unwrap();
+    let colon = head
+        .find(':')
+        .ok_or_else(|| format!("no value in '{}'", line))?;
+    let name = &head[..colon];
+    if name.is_empty() {
+        return Err(format!("empty name in '{}'", line));
+    }
+    let metric_type = fields
+        .next()
+        .ok_or_else(|| format!("no type in '{}'", line))?
+        .parse::<MetricType>()?;
+    let values: Vec<&str> = head[colon + 1..].split(':').collect();
+    for value in &values {
+        let valid = match metric_type {
+            MetricType::Set => !value.is_empty(),
+            _ => value.parse::<f64>().map(|v| v.is_finite()).unwrap_or(false),
+        };
+        if !valid {
+            return Err(format!("invalid value '{}' in '{}'", value, line));
+        }
+    }
+
+    let mut metric = Metric {
+        name,
+        values,
+        metric_type,
+        sample_rate: None,
+        tags: Vec::new(),
+        container_id: None,
+        timestamp: None,
+    };
+    for field in fields {
+        if let Some(rate) = field.strip_prefix('@') {
+            let rate = rate
+                .parse::<f64>()
+                .ok()
+                .filter(|r| *r > 0.0 && *r <= 1.0)
+                .ok_or_else(|| format!("invalid sample rate '{}' in '{}'", rate, line))?;
+            metric.sample_rate = Some(rate);
+        } else if let Some(tags) = field.strip_prefix('#') {
+            metric.tags = parse_tags(tags, line)?;
+        } else if let Some(id) = field.strip_prefix("c:") {
+            metric.container_id = Some(id);
+        } else if let Some(ts) = field.strip_prefix('T') {
+            metric.timestamp = Some(parse_timestamp(ts, line)?);
+        } else {
+            return Err(format!("unknown field '{}' in '{}'", field, line));
+        }
+    }
+    Ok(metric)
+}
+
+fn parse_event(line: &str) -> Result<Event<'_>, String> {
+    let invalid = || format!("invalid event header in '{}'", line);
+    let close = line.find("}:").ok_or_else(invalid)?;
+    let mut lens = line[3..close].split(',').map(|l| l.parse::<usize>());
+    let (title_len, text_len) = match (lens.next(), lens.next(), lens.next()) {
+        (Some(Ok(title)), Some(Ok(text)), None) => (title, text),
+        _ => return Err(invalid()),
+    };
+    let body = &line[close + 2..];
+    let short = || format!("event shorter than its header claims in '{}'", line);
+    let title = body.get(..title_len).ok_or_else(short)?;
+    let rest = body[title_len..].strip_prefix('|').ok_or_else(short)?;
+    let text = rest.get(..text_len).ok_or_else(short)?;
+
+    let mut event = Event {
+        title,
+        text,
+        tags: Vec::new(),
+    };
+    let rest = &rest[text_len..];
+    if rest.is_empty() {
+        return Ok(event);
+    }
+    let rest = rest
+        .strip_prefix('|')
+        .ok_or_else(|| format!("event longer than its header claims in '{}'", line))?;
+    for field in rest.split('|') {
+        if let Some(ts) = field.strip_prefix("d:") {
+            parse_timestamp(ts, line)?;
+        } else if let Some(priority) = field.strip_prefix("p:") {
+            if priority != "normal" && priority != "low" {
+                return Err(format!("invalid priority '{}' in '{}'", priority, line));
+            }
+        } else if let Some(alert) = field.strip_prefix("t:") {
+            if !["error", "warning", "info", "success"].contains(&alert) {
+                return Err(format!("invalid alert type '{}' in '{}'", alert, line));
+            }
+        } else if let Some(tags) = field.strip_prefix('#') {
+            event.tags = parse_tags(tags, line)?;
+        } else if !(field.starts_with("h:") || field.starts_with("k:") || field.starts_with("s:")) {
+            return Err(format!("unknown field '{}' in '{}'", field, line));
+        }
+    }
+    Ok(event)
+}
+
+fn parse_service_check(line: &str) -> Result<ServiceCheck<'_>, String> {
+    // the message runs to the end of the line, pipes and all
+    let fields = match line.find("|m:") {
+        Some(at) => &line[..at],
+        None => line,
+    };
+    let mut fields = fields.split('|').skip(1);
+    let name = fields
+        .next()
+        .filter(|n| !n.is_empty())
+        .ok_or_else(|| format!("no service check name in '{}'", line))?;
+    let status = fields
+        .next()
+        .and_then(|s| s.parse::<u8>().ok())
+        .filter(|s| *s <= 3)
+        .ok_or_else(|| format!("invalid service check status in '{}'", line))?;
+
+    let mut check = ServiceCheck {
+        name,
+        status,
+        tags: Vec::new(),
+    };
+    for field in fields {
+        if let Some(ts) = field.strip_prefix("d:") {
+            parse_timestamp(ts, line)?;
+        } else if let Some(tags) = field.strip_prefix('#') {
+            check.tags = parse_tags(tags, line)?;
+        } else if !field.starts_with("h:") {
+            return Err(format!("unknown field '{}' in '{}'", field, line));
+        }
+    }
+    Ok(check)
+}
+
+fn parse_tags<'a>(tags: &'a str, line: &str) -> Result<Vec<&'a str>, String> {
+    let tags: Vec<&str> = tags.split(',').collect();
+    if tags.iter().any(|t| t.is_empty()) {
+        return Err(format!("empty tag in '{}'", line));
+    }
+    Ok(tags)
+}
+
+fn parse_timestamp(ts: &str, line: &str) -> Result<u64, String> {
+    ts.parse::<u64>()
+        .map_err(|_| format!("invalid timestamp '{}' in '{}'", ts, line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use generator::dogstatsd::DogStatsd;
+    use generator::statsd::{StatsdConfig, StatsdGenerator};
+
+    fn metric(line: &str) -> Metric<'_> {
+        match parse_line(line) {
+            Ok(Line::Metric(metric)) => metric,
+            other => panic!("expected a metric from '{}', got {:?}", line, other),
+        }
+    }
+
+    #[test]
+    fn plain_lines() {
+        let m = metric("a.b:12|c");
+        assert_eq!((m.name, m.values.clone()), ("a.b", vec!["12"]));
+        assert_eq!(m.metric_type, MetricType::Counter);
+        assert_eq!((m.sample_rate, m.rate(), m.total()), (None, 1.0, 12.0));
+
+        let m = metric("lat:3.5|ms|@0.25");
+        assert_eq!(
+            (m.metric_type, m.sample_rate),
+            (MetricType::Timer, Some(0.25))
+        );
+
+        // packed values, summed, and set members, counted
+        assert_eq!(metric("a:1:2:3|h").total(), 6.0);
+        assert_eq!(metric("a:x:y|s").total(), 2.0);
+        assert_eq!(metric("a:-0|g").total(), 0.0);
+    }
+
+    #[test]
+    fn dogstatsd_metric_fields() {
+        let m = metric("a:1|d|@0.5|#env:prod,role|c:abc123|T1700000000");
+        assert_eq!(m.metric_type, MetricType::Distribution);
+        assert_eq!(m.sample_rate, Some(0.5));
+        assert_eq!(m.tags, vec!["env:prod", "role"]);
+        assert_eq!(m.container_id, Some("abc123"));
+        assert_eq!(m.timestamp, Some(1_700_000_000));
+    }
+
+    #[test]
+    fn events() {
+        let line = "_e{5,11}:title|hello|world|d:1700000000|h:host|p:low|t:info|#a:b";
+        match parse_line(line) {
+            Ok(Line::Event(e)) => {
+                assert_eq!(
+                    (e.title, e.text, e.tags),
+                    ("title", "hello|world", vec!["a:b"])
+                )
+            }
+            other => panic!("expected an event, got {:?}", other),
+        }
+        match parse_line("_e{1,2}:t|xy") {
+            Ok(Line::Event(e)) => assert_eq!((e.title, e.text), ("t", "xy")),
+            other => panic!("expected an event, got {:?}", other),
+        }
+        // header lengths that disagree with the body
+        assert!(parse_line("_e{6,2}:title|xy").is_err());
+        assert!(parse_line("_e{4,2}:title|xy").is_err());
+        assert!(parse_line("_e{5,3}:title|xy").is_err());
+        assert!(parse_line("_e{5,1}:title|xy").is_err());
+        assert!(parse_line("_e{5}:title|xy").is_err());
+        assert!(parse_line("_e{5,x}:title|xy").is_err());
+        assert!(parse_line("_e{1,1}:t|x|p:urgent").is_err());
+        assert!(parse_line("_e{1,1}:t|x|t:fatal").is_err());
+    }
+
+    #[test]
+    fn service_checks() {
+        match parse_line("_sc|db.up|2|d:1700000000|h:host|#a:b|m:down | since | noon") {
+            Ok(Line::ServiceCheck(sc)) => {
+                assert_eq!((sc.name, sc.status, sc.tags), ("db.up", 2, vec!["a:b"]))
+            }
+            other => panic!("expected a service check, got {:?}", other),
+        }
+        assert!(parse_line("_sc|db.up|4").is_err());
+        assert!(parse_line("_sc||0").is_err());
+        assert!(parse_line("_sc|db.up").is_err());
+        assert!(parse_line("_sc|db.up|0|x:1").is_err());
+    }
+
+    #[test]
+    fn rejected_input() {
+        for line in &[
+            "a:NaN|c",
+            "a:inf|g",
+            "a:-inf|ms",
+            "a:1:nan|h",
+            "a:|c",
+            "a:1|c|@0",
+            "a:1|c|@1.5",
+            "a:1|c|@-1",
+            "a:1|c|#",
+            "a:1|c|#a,,b",
+            "a:1|c|Tsoon",
+            "a:1|c|x:1",
+            "a:1|q",
+            "a:1",
+            ":1|c",
+            "a|c",
+            "a:|s",
+        ] {
+            assert!(parse_line(line).is_err(), "accepted '{}'", line);
+        }
+    }
+
+    #[test]
+    fn generated_lines_parse() {
+        let config = StatsdConfig {
+            pool_size: 200,
+            mix: "g=1,c=1,ms=1,h=1,s=1,d=1".parse().unwrap(),
+            sample_rates: "c=0.5,ms=0.1,d=0.25".parse().unwrap(),
+            dogstatsd: Some(DogStatsd {
+                tags: 3,
+                tag_cardinality: 4,
+                events: 10,
+                service_checks: 10,
+                container_ids: 5,
+                timestamps: true,
+            }),
+            ..StatsdConfig::default()
+        };
+        let mut gen = StatsdGenerator::new(3, &config);
+        let (mut metrics, mut events, mut checks) = (0, 0, 0);
+        for _ in 0..2_000 {
+            let mut buf = String::new();
+            gen.fill_packet(&mut buf);
+            for line in buf.lines() {
+                match parse_line(line) {
+                    Ok(Line::Metric(_)) => metrics += 1,
+                    Ok(Line::Event(_)) => events += 1,
+                    Ok(Line::ServiceCheck(_)) => checks += 1,
+                    Err(e) => panic!("{}", e),
+                }
+            }
+        }
+        assert!(metrics > 0 && events > 0 && checks > 0);
+
+        let config = StatsdConfig {
+            pool_size: 100,
+            ..StatsdConfig::default()
+        };
+        let mut gen = StatsdGenerator::new(3, &config);
+        for _ in 0..500 {
+            let mut buf = String::new();
+            gen.fill_packet(&mut buf);
+            assert!(buf.lines().all(|l| parse_line(l).is_ok()), "{}", buf);
+        }
+    }
+}
@@ -0,0 +1,187 @@
+//! Scenario files
+//!
+//! A scenario captures the shape of a workload so that it can be replayed
+//! without retyping a long command line. It is a plain text file of
+//! `key = value` lines, the keys being the emitter's long option names.
+//! Blank lines and lines starting with `#` are ignored, while keys the
+//! emitter does not take from scenarios are rejected. Options given on the
+//! command line win over the scenario, which wins over the defaults.
+//!
+//! ```text
+//! # production web tier
+//! mix = c=60,g=30,ms=10
+//! ```
+
+use clap::ArgMatches;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Settings loaded from a scenario file
+#[derive(Debug, Clone, Default)]
+pub struct Scenario {
+    settings: HashMap<String, String>,
+}
+
+impl Scenario {
+    /// Load a scenario from `path`, which may only set the options in `known`
+    pub fn load<P: AsRef<Path>>(path: P, known: &[&str]) -> io::Result<Scenario> {
+        let mut body = String::new();
+        File::open(path)?.read_to_string(&mut body)?;
+        Scenario::parse(&body, known)
+    }
+
+    /// Parse a scenario from the text of a scenario file
+    pub fn parse(body: &str, known: &[&str]) -> io::Result<Scenario> {
+        let mut settings = HashMap::new();
+        for (num, line) in body.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.find('=') {
+                Some(idx) => {
+                    let key = line[..idx].trim();
+                    if !known.contains(&key) {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("line {} sets unknown option '{}'", num + 1, key),
+                        ));
+                    }
+                    settings.insert(key.to_string(), line[idx + 1..].trim().to_string());
+                }
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("line {} is not 'key = value'", num + 1),
+                    ))
+                }
+            }
+        }
+        Ok(Scenario { settings })
+    }
+
+    /// The scenario's value for `key`
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.settings.get(key).map(|s| s.as_str())
+    }
+
+    /// The value of option `key`: from the command line if given there,
+    /// else from the scenario, else the option's default
+    pub fn value(&self, matches: &ArgMatches, key: &str) -> Option<String> {
+        if matches.occurrences_of(key) == 0 {
+            if let Some(value) = self.get(key) {
+                return Some(value.to_string());
+            }
+        }
+        matches.value_of(key).map(|s| s.to_string())
+    }
+
+    /// The value of option `key`, as `value` finds it, parsed as a `T`
+    pub fn parse_value<T>(&self, matches: &ArgMatches, key: &str) -> Result<Option<T>, String>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        match self.value(matches, key) {
+            Some(value) => value
+                .parse::<T>()
+                .map(Some)
+                .map_err(|e| format!("invalid {} '{}': {}", key, value, e)),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether flag `key` is set on the command line or, as `key = true`,
+    /// in the scenario. A scenario value other than true or false is an
+    /// error.
+    pub fn is_present(&self, matches: &ArgMatches, key: &str) -> Result<bool, String> {
+        match self.get(key) {
+            None | Some("false") => Ok(matches.is_present(key)),
+            Some("true") => Ok(true),
+            Some(value) => Err(format!(
+                "invalid {} '{}': expected true or false",
+                key, value
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{App, Arg};
+
+    const KNOWN: &[&str] = &["mix", "pool_size", "timestamps"];
+
+    fn matches(args: &[&str]) -> ArgMatches<'static> {
+        App::new("test")
+            .arg(
+                Arg::with_name("mix")
+                    .long("mix")
+                    .takes_value(true)
+                    .default_value("c=1"),
+            )
+            .arg(
+                Arg::with_name("pool_size")
+                    .long("pool_size")
+                    .takes_value(true),
+            )
+            .arg(Arg::with_name("timestamps").long("timestamps"))
+            .get_matches_from(args)
+    }
+
+    #[test]
+    fn command_line_wins_over_scenario_over_default() {
+        let scenario = Scenario::parse("# shape\n\nmix = g=1\n", KNOWN).unwrap();
+        let given = matches(&["test", "--mix", "ms=1"]);
+        assert_eq!(scenario.value(&given, "mix"), Some("ms=1".into()));
+        assert_eq!(
+            scenario.value(&matches(&["test"]), "mix"),
+            Some("g=1".into())
+        );
+        let empty = Scenario::default();
+        assert_eq!(empty.value(&matches(&["test"]), "mix"), Some("c=1".into()));
+        assert_eq!(empty.value(&matches(&["test"]), "pool_size"), None);
+    }
+
+    #[test]
+    fn rejects_unknown_keys_and_bare_lines() {
+        assert!(Scenario::parse("pool_sise = 10", KNOWN).is_err());
+        assert!(Scenario::parse("rate = 10", KNOWN).is_err());
+        assert!(Scenario::parse("pool_size", KNOWN).is_err());
+    }
+
+    #[test]
+    fn values_parse_or_name_the_key() {
+        let args = matches(&["test"]);
+        let scenario = Scenario::parse("pool_size = 10", KNOWN).unwrap();
+        assert_eq!(
+            scenario.parse_value::<usize>(&args, "pool_size"),
+            Ok(Some(10))
+        );
+        let scenario = Scenario::parse("pool_size = ten", KNOWN).unwrap();
+        let err = scenario
+            .parse_value::<usize>(&args, "pool_size")
+            .unwrap_err();
+        assert!(err.contains("pool_size 'ten'"), "{}", err);
+        let empty = Scenario::default();
+        assert_eq!(empty.parse_value::<usize>(&args, "pool_size"), Ok(None));
+    }
+
+    #[test]
+    fn flags_are_true_or_false() {
+        let set = matches(&["test", "--timestamps"]);
+        let unset = matches(&["test"]);
+        let scenario = Scenario::parse("timestamps = true", KNOWN).unwrap();
+        assert_eq!(scenario.is_present(&unset, "timestamps"), Ok(true));
+        let scenario = Scenario::parse("timestamps = false", KNOWN).unwrap();
+        assert_eq!(scenario.is_present(&unset, "timestamps"), Ok(false));
+        assert_eq!(scenario.is_present(&set, "timestamps"), Ok(true));
+        let scenario = Scenario::parse("timestamps = yes", KNOWN).unwrap();
+        assert!(scenario.is_present(&unset, "timestamps").is_err());
+    }
+}
@@ -0,0 +1,553 @@
+//! Saturation-point search
+//!
+//! An emitter alone knows only what it wrote. To find the rate a server can
+//! sustain we pair it with a `Feedback` signal -- a count of what the
+//! receiver acknowledged or of what it dropped -- and bisect on the rate
+//! between a known-good and a known-bad bound.
+
+use clap::{Arg, ArgMatches};
+use profile::{parse_bounds, parse_duration};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+/// Times a feedback read is attempted before its trial is given up on
+const READ_ATTEMPTS: usize = 3;
+
+/// What a feedback counter measures
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Signal {
+    /// The counter is the number of units the receiver accepted
+    Received,
+    /// The counter is the number of units the receiver dropped
+    Dropped,
+}
+
+impl FromStr for Signal {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Signal, String> {
+        match s {
+            "received" => Ok(Signal::Received),
+            "dropped" => Ok(Signal::Dropped),
+            _ => Err(format!("unknown feedback signal '{}'", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Source {
+    File(PathBuf),
+    Http { addr: String, path: String },
+}
+
+/// A monotonic counter scraped from a local file or an HTTP endpoint
+///
+/// If a key is set the counter is the number following the first line
+/// whose first token is that key, as in `key 42`, `key: 42` or `key=42`.
+/// Otherwise it is the first number in the document.
+#[derive(Debug, Clone)]
+pub struct Feedback {
+    source: Source,
+    key: Option<String>,
+    signal: Signal,
+}
+
+impl Feedback {
+    /// Create a new Feedback reading `location`, either a file path or an
+    /// `http://host:port/path` URL
+    pub fn new(location: &str, key: Option<String>, signal: Signal) -> Feedback {
+        let source = if let Some(rest) = location.strip_prefix("http://") {
+            let (addr, path) = match rest.find('/') {
+                Some(idx) => (&rest[..idx], &rest[idx..]),
+                None => (rest, "/"),
+            };
+            Source::Http {
+                addr: addr.to_string(),
+                path: path.to_string(),
+            }
+        } else {
+            Source::File(PathBuf::from(location))
+        };
+        Feedback {
+            source,
+            key,
+            signal,
+        }
+    }
+
+    /// What this feedback's counter measures
+    pub fn signal(&self) -> Signal {
+        self.signal
+    }
+
+    /// Read the current value of the counter
+    pub fn read(&self) -> io::Result<u64> {
+        let body = match self.source {
+            Source::File(ref path) => {
+                let mut body = String::new();
+                File::open(path)?.read_to_string(&mut body)?;
+                body
+            }
+            Source::Http { ref addr, ref path } => scrape(addr, path)?,
+        };
+        self.extract(&body).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "no counter found in feedback")
+        })
+    }
+
+    fn extract(&self, body: &str) -> Option<u64> {
+        match self.key {
+            Some(ref key) => body.lines().find_map(|line| {
+                let line = line.trim_start();
+                let end = line
+                    .find(|c: char| c == ':' || c == '=' || c.is_whitespace())
+                    .unwrap_or(line.len());
+                if &line[..end] == key {
+                    first_number(&line[end..])
+                } else {
+                    None
+                }
+            }),
+            None => first_number(body),
+        }
+    }
+
+    /// The fraction of `sent` units lost, given the counter read before and
+    /// after they were sent
+    pub fn loss(&self, sent: u64, before: u64, after: u64) -> f64 {
+        if sent == 0 {
+            return 0.0;
+        }
+        let delta = after.saturating_sub(before) as f64;
+        let loss = match self.signal {
+            Signal::Received => 1.0 - delta / sent as f64,
+            Signal::Dropped => delta / sent as f64,
+        };
+        loss.max(0.0)
+    }
+}
+
+fn first_number(s: &str) -> Option<u64> {
+    let start = s.find(|c: char| c.is_ascii_digit())?;
+    let digits: String = s[start..]
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    digits.parse::<u64>().ok()
+}
+
+fn scrape(addr: &str, path: &str) -> io::Result<String> {
+    let mut stream = TcpStream::connect(addr)?;
+    write!(
+        stream,
+        "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, addr
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    match response.find("\r\n\r\n") {
+        Some(idx) => Ok(response[idx + 4..].to_string()),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "malformed HTTP response",
+        )),
+    }
+}
+
+/// Bisection over emission rates
+///
+/// `low` is taken to be sustainable and `high` not. Each trial narrows the
+/// interval until `high` is within `precision` -- a fraction, 0.05 being 5%
+/// -- of `low`. Should no trial be sustained `low` is never itself tested,
+/// which `low_tested` tells.
+#[derive(Debug, Clone, Copy)]
+pub struct Search {
+    low: f64,
+    high: f64,
+    precision: f64,
+    low_tested: bool,
+}
+
+impl Search {
+    /// Create a new Search between `low` and `high` units per second
+    pub fn new(low: f64, high: f64, precision: f64) -> Result<Search, String> {
+        if !(low.is_finite() && high.is_finite() && 0.0 <= low && low < high) {
+            return Err(format!(
+                "search bounds {}..{} are not an increasing range",
+                low, high
+            ));
+        }
+        if !(precision.is_finite() && precision > 0.0) {
+            return Err(format!("search precision {} is not positive", precision));
+        }
+        Ok(Search {
+            low,
+            high,
+            precision,
+            low_tested: false,
+        })
+    }
+
+    /// The next rate to trial, None once the search has converged
+    pub fn next_rate(&self) -> Option<f64> {
+        if self.high - self.low <= self.low.max(1.0) * self.precision {
+            None
+        } else {
+            Some((self.low + self.high) / 2.0)
+        }
+    }
+
+    /// Record whether a trial at `rate` was sustained
+    pub fn record(&mut self, rate: f64, sustained: bool) {
+        if sustained {
+            self.low = rate;
+            self.low_tested = true;
+        } else {
+            self.high = rate;
+        }
+    }
+
+    /// The highest rate known to be sustainable
+    pub fn sustainable(&self) -> f64 {
+        self.low
+    }
+
+    /// Whether a trial sustained `sustainable`, rather than it being the
+    /// lower bound assumed so
+    pub fn low_tested(&self) -> bool {
+        self.low_tested
+    }
+}
+
+/// A search and what it takes to judge each of its trials, as given by the
+/// options `args` defines
+pub struct Trials {
+    pub search: Search,
+    pub feedback: Feedback,
+    /// Length of each trial, in seconds
+    pub trial: f64,
+    /// Time allowed after each trial for the receiver to catch up
+    pub settle: Duration,
+    /// Largest fraction of units lost for a trial to be sustained
+    pub max_loss: f64,
+    /// Largest lag behind schedule for a trial to be sustained
+    pub max_lag: Option<Duration>,
+}
+
+impl Trials {
+    /// Build the trials asked for by `matches`, None when not searching
+    pub fn from_matches(matches: &ArgMatches) -> Option<Trials> {
+        let (low, high) = parse_bounds(matches.value_of("search")?).unwrap();
+        let precision = matches
+            .value_of("precision")
+            .unwrap()
+            .parse::<f64>()
+            .unwrap();
+        let feedback = Feedback::new(
+            matches.value_of("feedback").unwrap(),
+            matches.value_of("feedback_key").map(|k| k.to_string()),
+            matches
+                .value_of("feedback_signal")
+                .unwrap()
+                .parse::<Signal>()
+                .unwrap(),
+        );
+        let settle = parse_duration(matches.value_of("settle").unwrap()).unwrap();
+        let search = Search::new(low, high, precision).unwrap_or_else(|e| {
+            clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit()
+        });
+        Some(Trials {
+            search,
+            feedback,
+            trial: parse_duration(matches.value_of("trial").unwrap()).unwrap(),
+            settle: Duration::from_secs_f64(settle),
+            max_loss: matches
+                .value_of("max_loss")
+                .unwrap()
+                .parse::<f64>()
+                .unwrap(),
+            max_lag: matches
+                .value_of("max_lag_ms")
+                .map(|s| Duration::from_millis(s.parse::<u64>().unwrap())),
+        })
+    }
+
+    /// Read the feedback counter, a failed read being retried after a
+    /// second's pause up to `READ_ATTEMPTS` times in all
+    pub fn read_feedback(&self) -> io::Result<u64> {
+        let mut attempt = 1;
+        loop {
+            match self.feedback.read() {
+                Ok(count) => return Ok(count),
+                Err(e) if attempt == READ_ATTEMPTS => return Err(e),
+                Err(_) => {
+                    attempt += 1;
+                    thread::sleep(Duration::from_secs(1));
+                }
+            }
+        }
+    }
+
+    /// Judge and report the trial at `rate`, which sent `sent` units and
+    /// fell at most `lag` behind schedule, the feedback counter reading
+    /// `before` and `after` it
+    ///
+    /// A trial whose feedback could not be read is reported as failed and
+    /// counted as saturated, the rate found being only ever one measured
+    /// sustainable.
+    pub fn conclude(
+        &mut self,
+        rate: f64,
+        sent: usize,
+        lag: Duration,
+        before: io::Result<u64>,
+        after: io::Result<u64>,
+    ) {
+        let (before, after) = match (before, after) {
+            (Ok(before), Ok(after)) => (before, after),
+            (Err(e), _) | (_, Err(e)) => {
+                println!(
+                    "TRIAL RATE: {} | SENT: {} | FEEDBACK UNREADABLE: {} | FAILED",
+                    rate as u64, sent, e
+                );
+                self.search.record(rate, false);
+                return;
+            }
+        };
+        let loss = self.feedback.loss(sent as u64, before, after);
+        let sustained = loss <= self.max_loss && self.max_lag.is_none_or(|max| lag <= max);
+        println!(
+            "TRIAL RATE: {} | SENT: {} | LOSS: {:.4} | MAX BEHIND SCHEDULE: {}us | {}",
+            rate as u64,
+            sent,
+            loss,
+            lag.as_micros(),
+            if sustained { "SUSTAINED" } else { "SATURATED" }
+        );
+        self.search.record(rate, sustained);
+    }
+
+    /// Report the rate the search converged on
+    pub fn report(&self) {
+        let rate = self.search.sustainable() as u64;
+        if self.search.low_tested() {
+            println!("MAX SUSTAINABLE RATE: {}", rate);
+        } else {
+            println!(
+                "MAX SUSTAINABLE RATE: {} | UNTESTED: no trial sustained, the lower bound is assumed",
+                rate
+            );
+        }
+    }
+}
+
+/// The options configuring a search, `--search` conflicting with the
+/// options named in `conflicts`
+pub fn args(conflicts: &[&'static str]) -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("search")
+            .long("search")
+            .takes_value(true)
+            .help("Search for the max sustainable rate between bounds like '10k..1M'")
+            .validator(|s| parse_bounds(&s).map(|_| ()))
+            .conflicts_with_all(conflicts)
+            .requires("feedback"),
+        Arg::with_name("feedback")
+            .long("feedback")
+            .takes_value(true)
+            .help("File path or http:// URL holding the receiver's counter, in rate_unit"),
+        Arg::with_name("feedback_key")
+            .long("feedback_key")
+            .takes_value(true)
+            .help("Name of the counter in the feedback document, else its first number"),
+        Arg::with_name("feedback_signal")
+            .long("feedback_signal")
+            .takes_value(true)
+            .possible_values(&["received", "dropped"])
+            .default_value("received")
+            .help("Whether the feedback counts units received or dropped"),
+        Arg::with_name("trial")
+            .long("trial")
+            .takes_value(true)
+            .default_value("10s")
+            .validator(|s| parse_duration(&s).map(|_| ()))
+            .help("Length of each search trial"),
+        Arg::with_name("settle")
+            .long("settle")
+            .takes_value(true)
+            .default_value("2s")
+            .validator(|s| parse_duration(&s).map(|_| ()))
+            .help("Time allowed after each trial for the receiver to catch up"),
+        Arg::with_name("max_loss")
+            .long("max_loss")
+            .takes_value(true)
+            .default_value("0.001")
+            .validator(|s| match s.parse::<f64>() {
+                Ok(f) if (0.0..=1.0).contains(&f) => Ok(()),
+                _ => Err(format!("'{}' is not a fraction between 0 and 1", s)),
+            })
+            .help("Largest fraction of units lost for a trial to be sustained"),
+        Arg::with_name("max_lag_ms")
+            .long("max_lag_ms")
+            .takes_value(true)
+            .validator(|s| s.parse::<u64>().map(|_| ()).map_err(|e| e.to_string()))
+            .help("Largest lag behind schedule, in milliseconds, for a trial to be sustained"),
+        Arg::with_name("precision")
+            .long("precision")
+            .takes_value(true)
+            .default_value("0.05")
+            .validator(|s| match s.parse::<f64>() {
+                Ok(p) if p > 0.0 && p.is_finite() => Ok(()),
+                _ => Err(format!("'{}' is not a positive fraction", s)),
+            })
+            .help("Stop searching once the bounds are within this fraction"),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trials(signal: Signal) -> Trials {
+        Trials {
+            search: Search::new(100.0, 200.0, 0.05).unwrap(),
+            feedback: Feedback::new("/nonexistent", None, signal),
+            trial: 1.0,
+            settle: Duration::from_secs(0),
+            max_loss: 0.01,
+            max_lag: None,
+        }
+    }
+
+    #[test]
+    fn counters_extract_by_key_or_first_number() {
+        let body = "# counters\nsent 7\ndropped: 12\nreceived=40 of 52\nreceived_ok 3\n";
+        let keyed = |key: &str| Feedback::new("f", Some(key.to_string()), Signal::Received);
+        assert_eq!(keyed("sent").extract(body), Some(7));
+        assert_eq!(keyed("dropped").extract(body), Some(12));
+        assert_eq!(keyed("received").extract(body), Some(40));
+        assert_eq!(keyed("received_ok").extract(body), Some(3));
+        assert_eq!(keyed("missing").extract(body), None);
+        let first = Feedback::new("f", None, Signal::Received);
+        assert_eq!(first.extract(body), Some(7));
+        assert_eq!(first.extract("no digits here"), None);
+    }
+
+    #[test]
+    fn locations_parse() {
+        let http = Feedback::new("http://localhost:9090/metrics", None, Signal::Received);
+        assert_eq!(
+            http.source,
+            Source::Http {
+                addr: "localhost:9090".to_string(),
+                path: "/metrics".to_string(),
+            }
+        );
+        let bare = Feedback::new("http://localhost:9090", None, Signal::Received);
+        assert_eq!(
+            bare.source,
+            Source::Http {
+                addr: "localhost:9090".to_string(),
+                path: "/".to_string(),
+            }
+        );
+        let file = Feedback::new("/tmp/count", None, Signal::Received);
+        assert_eq!(file.source, Source::File(PathBuf::from("/tmp/count")));
+    }
+
+    #[test]
+    fn loss_follows_signal() {
+        let received = Feedback::new("f", None, Signal::Received);
+        assert!((received.loss(100, 1000, 1090) - 0.1).abs() < 1e-9);
+        assert_eq!(received.loss(100, 1000, 1100), 0.0);
+        // more received than sent, as when other senders share the server
+        assert_eq!(received.loss(100, 1000, 1200), 0.0);
+        // a counter reset reads as everything lost
+        assert_eq!(received.loss(100, 1000, 10), 1.0);
+        assert_eq!(received.loss(0, 1000, 1000), 0.0);
+
+        let dropped = Feedback::new("f", None, Signal::Dropped);
+        assert_eq!(dropped.loss(100, 5, 30), 0.25);
+        assert_eq!(dropped.loss(100, 5, 5), 0.0);
+        assert_eq!(dropped.loss(100, 5, 0), 0.0);
+    }
+
+    #[test]
+    fn search_converges_on_threshold() {
+        for &threshold in &[150.0, 1234.5, 99_000.0] {
+            let mut search = Search::new(100.0, 100_000.0, 0.01).unwrap();
+            let mut trials = 0;
+            while let Some(rate) = search.next_rate() {
+                assert!(100.0 < rate && rate < 100_000.0);
+                search.record(rate, rate <= threshold);
+                trials += 1;
+            }
+            assert!(trials < 20);
+            assert!(search.low_tested());
+            assert!(search.sustainable() <= threshold);
+            assert!(threshold - search.sustainable() <= threshold * 0.01);
+        }
+    }
+
+    #[test]
+    fn search_below_low_is_untested() {
+        let mut search = Search::new(100.0, 1000.0, 0.05).unwrap();
+        while let Some(rate) = search.next_rate() {
+            search.record(rate, false);
+        }
+        assert_eq!(search.sustainable(), 100.0);
+        assert!(!search.low_tested());
+    }
+
+    #[test]
+    fn search_rejects_bad_bounds() {
+        assert!(Search::new(10.0, 10.0, 0.05).is_err());
+        assert!(Search::new(20.0, 10.0, 0.05).is_err());
+        assert!(Search::new(-1.0, 10.0, 0.05).is_err());
+        assert!(Search::new(1.0, f64::INFINITY, 0.05).is_err());
+        assert!(Search::new(1.0, 10.0, 0.0).is_err());
+        assert!(Search::new(1.0, 10.0, f64::NAN).is_err());
+    }
+
+    #[test]
+    fn trials_judge_loss_lag_and_unread_feedback() {
+        let mut t = trials(Signal::Received);
+        t.conclude(150.0, 100, Duration::from_millis(5), Ok(0), Ok(100));
+        assert_eq!(t.search.sustainable(), 150.0);
+        t.conclude(175.0, 100, Duration::from_millis(5), Ok(100), Ok(150));
+        assert_eq!(t.search.sustainable(), 150.0);
+        assert_eq!(t.search.next_rate(), Some(162.5));
+
+        let mut lagging = trials(Signal::Dropped);
+        lagging.max_lag = Some(Duration::from_millis(1));
+        lagging.conclude(150.0, 100, Duration::from_millis(5), Ok(0), Ok(0));
+        assert!(!lagging.search.low_tested());
+        assert_eq!(lagging.search.next_rate(), Some(125.0));
+
+        let mut unread = trials(Signal::Received);
+        let err = || Err(io::Error::other("scrape failed"));
+        unread.conclude(150.0, 100, Duration::from_millis(0), Ok(0), err());
+        unread.conclude(125.0, 100, Duration::from_millis(0), err(), Ok(100));
+        assert!(!unread.search.low_tested());
+        assert_eq!(unread.search.next_rate(), Some(112.5));
+    }
+
+    #[test]
+    fn feedback_reads_files() {
+        let path = ::std::env::temp_dir().join(format!("search_feedback_{}", ::std::process::id()));
+        ::std::fs::write(&path, "drops 3\naccepted 42\n").unwrap();
+        let feedback = Feedback::new(
+            path.to_str().unwrap(),
+            Some("accepted".to_string()),
+            Signal::Received,
+        );
+        assert_eq!(feedback.read().unwrap(), 42);
+        ::std::fs::remove_file(&path).unwrap();
+        assert!(feedback.read().is_err());
+    }
+}
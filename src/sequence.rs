@@ -0,0 +1,269 @@
+//! Sequence numbers for end-to-end loss accounting
+//!
+//! An emitter tracing its output gives each of its senders -- a thread, or
+//! each connection or destination a thread takes turns over -- a source name
+//! and numbers every packet or payload the sender sends from 0, carrying the
+//! number as the value of a metric named `llrv.sequence.SOURCE`. A listener
+//! feeds the numbers it sees into a `Sequences`, which works out per source
+//! what was lost, delivered twice or delivered out of order.
+//!
+//! Accounting for a source starts at the first number the listener sees
+//! from it, so a listener started after its emitter does not count what was
+//! sent before it was listening as lost. Gaps are remembered for a window of
+//! `WINDOW` numbers. A number arriving later than that is counted as a
+//! duplicate, its gap having been written off as lost.
+
+use rand::{thread_rng, Rng};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+lazy_static! {
+    // told apart from other runs, and restarts, by a random id
+    static ref RUN: u32 = thread_rng().gen::<u32>();
+}
+
+/// The name prefix of sequence metrics, followed by the source
+pub const PREFIX: &str = "llrv.sequence.";
+
+/// How far behind the highest number seen a gap is still waited on
+pub const WINDOW: u64 = 1 << 16;
+
+/// The source name of emitter sender `sender`
+///
+/// Sources are the run's random id followed by the sender, so that runs,
+/// and restarts within a run, are told apart.
+pub fn source(sender: usize) -> String {
+    format!("{:08x}_{}", *RUN, sender)
+}
+
+/// The name of the sequence metric of `source`
+pub fn metric_name(source: &str) -> String {
+    format!("{}{}", PREFIX, source)
+}
+
+/// The source of a sequence metric named `name`, None for other metrics
+pub fn parse_source(name: &str) -> Option<&str> {
+    name.strip_prefix(PREFIX).filter(|s| !s.is_empty())
+}
+
+/// Accounting for the numbers received from one source
+#[derive(Debug, Clone, Default)]
+pub struct Tracker {
+    /// The lowest number accounted for, the first seen unless a lower one
+    /// followed it within the window
+    start: u64,
+    /// One past the highest number seen
+    next: u64,
+    /// Numbers from `start` below `next` not yet seen, within the window
+    missing: BTreeSet<u64>,
+    received: u64,
+    expired: u64,
+    duplicated: u64,
+    reordered: u64,
+}
+
+impl Tracker {
+    /// Account for the arrival of `seq`
+    pub fn record(&mut self, seq: u64) {
+        self.received += 1;
+        if self.received == 1 {
+            self.start = seq;
+            self.next = seq + 1;
+        } else if seq < self.start && seq >= self.next.saturating_sub(WINDOW) {
+            // reordered ahead of the first number seen, opening a gap
+            self.missing.extend(seq + 1..self.start);
+            self.start = seq;
+            self.reordered += 1;
+        } else if seq >= self.next {
+            // numbers skipped past the window are lost already
+            let floor = seq.saturating_sub(WINDOW).max(self.next);
+            self.expired += floor - self.next;
+            self.missing.extend(floor..seq);
+            self.next = seq + 1;
+            let horizon = self.next.saturating_sub(WINDOW);
+            while let Some(&oldest) = self.missing.iter().next() {
+                if oldest >= horizon {
+                    break;
+                }
+                self.missing.remove(&oldest);
+                self.expired += 1;
+            }
+        } else if self.missing.remove(&seq) {
+            self.reordered += 1;
+        } else {
+            self.duplicated += 1;
+        }
+    }
+
+    /// Numbers received, duplicates included
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    /// Numbers below the highest seen that have not arrived
+    pub fn lost(&self) -> u64 {
+        self.expired + self.missing.len() as u64
+    }
+
+    /// Numbers received more than once
+    pub fn duplicated(&self) -> u64 {
+        self.duplicated
+    }
+
+    /// Numbers that arrived after a higher one
+    pub fn reordered(&self) -> u64 {
+        self.reordered
+    }
+}
+
+impl fmt::Display for Tracker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let expected = (self.next - self.start).max(1) as f64;
+        write!(
+            f,
+            "RECEIVED: {} | LOST: {} ({:.3}%) | DUPLICATED: {} | REORDERED: {}",
+            self.received,
+            self.lost(),
+            100.0 * self.lost() as f64 / expected,
+            self.duplicated,
+            self.reordered
+        )
+    }
+}
+
+/// Accounting for every source a listener hears from
+#[derive(Debug, Clone, Default)]
+pub struct Sequences {
+    sources: BTreeMap<String, Tracker>,
+}
+
+impl Sequences {
+    /// Account for the arrival of `seq` from `source`
+    pub fn record(&mut self, source: &str, seq: u64) {
+        if !self.sources.contains_key(source) {
+            self.sources.insert(source.to_string(), Tracker::default());
+        }
+        self.sources.get_mut(source).unwrap().record(seq);
+    }
+
+    /// Whether any sequence numbers have been received
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    /// Print a line of accounting per source
+    pub fn report(&self) {
+        for (source, tracker) in &self.sources {
+            println!("{:<2}SOURCE {}: {}", "", source, tracker);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker(seqs: &[u64]) -> Tracker {
+        let mut tracker = Tracker::default();
+        for seq in seqs {
+            tracker.record(*seq);
+        }
+        tracker
+    }
+
+    fn counts(tracker: &Tracker) -> (u64, u64, u64, u64) {
+        (
+            tracker.received(),
+            tracker.lost(),
+            tracker.duplicated(),
+            tracker.reordered(),
+        )
+    }
+
+    #[test]
+    fn in_order_numbers_lose_nothing() {
+        assert_eq!(counts(&tracker(&[0, 1, 2, 3])), (4, 0, 0, 0));
+    }
+
+    #[test]
+    fn gaps_are_lost_until_filled() {
+        assert_eq!(counts(&tracker(&[2, 3, 6])), (3, 2, 0, 0));
+        assert_eq!(counts(&tracker(&[2, 3, 6, 4, 5])), (5, 0, 0, 2));
+    }
+
+    #[test]
+    fn accounting_starts_at_the_first_number() {
+        // a listener started after its emitter loses nothing before
+        assert_eq!(counts(&tracker(&[1000, 1001, 1002])), (3, 0, 0, 0));
+        // a lower number just behind the first opens a gap down to it
+        assert_eq!(counts(&tracker(&[2, 3, 0])), (3, 1, 0, 1));
+        assert_eq!(counts(&tracker(&[2, 3, 0, 1])), (4, 0, 0, 2));
+        // one behind the window is too late to count
+        let mut late = tracker(&[WINDOW + 5]);
+        late.record(4);
+        assert_eq!(counts(&late), (2, 0, 1, 0));
+        assert!(late.to_string().contains("LOST: 0 (0.000%)"));
+    }
+
+    #[test]
+    fn late_numbers_are_reordered() {
+        assert_eq!(counts(&tracker(&[1, 0, 3, 2, 4])), (5, 0, 0, 2));
+    }
+
+    #[test]
+    fn repeats_are_duplicated() {
+        assert_eq!(counts(&tracker(&[0, 1, 1, 2, 0])), (5, 0, 2, 0));
+        // a filled gap filled again is a duplicate, not a reorder
+        assert_eq!(counts(&tracker(&[0, 2, 1, 1])), (4, 0, 1, 1));
+    }
+
+    #[test]
+    fn gaps_expire_past_the_window() {
+        // a jump wider than the window writes off what it skipped at once
+        let mut far = tracker(&[0, WINDOW + 10]);
+        assert_eq!(counts(&far), (2, WINDOW + 9, 0, 0));
+        // the window reaching back from one past the highest number seen
+        assert_eq!(far.missing.len() as u64, WINDOW - 1);
+        // a number within the window still fills its gap
+        far.record(20);
+        assert_eq!(counts(&far), (3, WINDOW + 8, 0, 1));
+        // one behind the window is too late, its gap written off
+        far.record(5);
+        assert_eq!(counts(&far), (4, WINDOW + 8, 1, 1));
+
+        // gaps left behind as the window moves on expire too
+        let mut moving = tracker(&[0, 2]);
+        moving.record(WINDOW + 2);
+        assert_eq!(moving.missing.iter().next(), Some(&3));
+        assert_eq!(moving.lost(), WINDOW);
+        moving.record(1);
+        assert_eq!(counts(&moving), (4, WINDOW, 1, 0));
+    }
+
+    #[test]
+    fn sources_are_tracked_apart() {
+        let mut sequences = Sequences::default();
+        assert!(sequences.is_empty());
+        for seq in 0..3 {
+            sequences.record("a", seq);
+            sequences.record("b", seq * 2);
+        }
+        assert_eq!(counts(&sequences.sources["a"]), (3, 0, 0, 0));
+        assert_eq!(counts(&sequences.sources["b"]), (3, 2, 0, 0));
+    }
+
+    #[test]
+    fn sources_parse_from_metric_names() {
+        let name = metric_name("abc_1");
+        assert_eq!(parse_source(&name), Some("abc_1"));
+        assert_eq!(parse_source(PREFIX), None);
+        assert_eq!(parse_source("llrv.other"), None);
+    }
+
+    #[test]
+    fn senders_share_the_run_id() {
+        let (first, second) = (source(0), source(1));
+        assert!(first.ends_with("_0") && second.ends_with("_1"));
+        assert_eq!(first[..8], second[..8]);
+    }
+}
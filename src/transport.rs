@@ -0,0 +1,245 @@
+//! Transports shared by the emitters and listeners
+//!
+//! Addresses are given either as a plain host -- a name or an IPv4 or IPv6
+//! literal, paired with a port option -- or as a Unix socket path with a
+//! scheme prefix:
+//!
+//! * `unix://path` a Unix stream socket, for the native protocol
+//! * `unix-dgram://path` a Unix datagram socket, for statsd
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+#[cfg(unix)]
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::net::{UnixDatagram, UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Where to send to or listen on
+#[derive(Debug, Clone, PartialEq)]
+pub enum Endpoint {
+    /// A host name or IP address, the port being given separately
+    Inet(String),
+    /// A Unix stream socket
+    Unix(PathBuf),
+    /// A Unix datagram socket
+    UnixDgram(PathBuf),
+}
+
+impl FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Endpoint, String> {
+        if let Some(path) = s.strip_prefix("unix-dgram://") {
+            unix_path(path).map(Endpoint::UnixDgram)
+        } else if let Some(path) = s.strip_prefix("unix://") {
+            unix_path(path).map(Endpoint::Unix)
+        } else if s.contains("://") {
+            Err(format!("unknown scheme in '{}'", s))
+        } else {
+            Ok(Endpoint::Inet(
+                s.trim_start_matches('[').trim_end_matches(']').to_string(),
+            ))
+        }
+    }
+}
+
+#[cfg(unix)]
+fn unix_path(path: &str) -> Result<PathBuf, String> {
+    if path.is_empty() {
+        Err("unix socket path is empty".into())
+    } else {
+        Ok(PathBuf::from(path))
+    }
+}
+
+#[cfg(not(unix))]
+fn unix_path(_path: &str) -> Result<PathBuf, String> {
+    Err("unix sockets are not supported on this platform".into())
+}
+
+/// Resolve `host` through `ToSocketAddrs`, dropping duplicate addresses
+pub fn resolve(host: &str, port: u16) -> Vec<SocketAddr> {
+    let mut addrs: Vec<SocketAddr> = Vec::new();
+    if let Ok(srv) = (host, port).to_socket_addrs() {
+        for addr in srv {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+    }
+    addrs
+}
+
+/// A connected stream, over TCP or a Unix socket
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.flush(),
+            #[cfg(unix)]
+            Stream::Unix(ref mut s) => s.flush(),
+        }
+    }
+}
+
+/// Connect a stream to `endpoint`, trying each resolved address in turn
+pub fn connect(endpoint: &Endpoint, port: u16) -> Option<Stream> {
+    match *endpoint {
+        Endpoint::Inet(ref host) => {
+            for ip in resolve(host, port) {
+                if let Ok(stream) = TcpStream::connect(ip) {
+                    return Some(Stream::Tcp(stream));
+                }
+            }
+            None
+        }
+        #[cfg(unix)]
+        Endpoint::Unix(ref path) => UnixStream::connect(path).ok().map(Stream::Unix),
+        _ => None,
+    }
+}
+
+/// A bound stream listener, over TCP or a Unix socket
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Listen on `endpoint`, replacing any stale Unix socket file
+    pub fn bind(endpoint: &Endpoint, port: u16) -> io::Result<Listener> {
+        match *endpoint {
+            Endpoint::Inet(ref host) => {
+                TcpListener::bind(&resolve(host, port)[..]).map(Listener::Tcp)
+            }
+            #[cfg(unix)]
+            Endpoint::Unix(ref path) => {
+                let _ = fs::remove_file(path);
+                UnixListener::bind(path).map(Listener::Unix)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a stream endpoint",
+            )),
+        }
+    }
+
+    /// Accept the next connection
+    pub fn accept(&self) -> io::Result<Stream> {
+        match *self {
+            Listener::Tcp(ref l) => l.accept().map(|(s, _)| Stream::Tcp(s)),
+            #[cfg(unix)]
+            Listener::Unix(ref l) => l.accept().map(|(s, _)| Stream::Unix(s)),
+        }
+    }
+}
+
+/// A bound datagram socket, over UDP or a Unix socket
+pub enum DatagramListener {
+    Udp(UdpSocket),
+    #[cfg(unix)]
+    Unix(UnixDatagram),
+}
+
+impl DatagramListener {
+    /// Listen on `endpoint`, replacing any stale Unix socket file
+    pub fn bind(endpoint: &Endpoint, port: u16) -> io::Result<DatagramListener> {
+        match *endpoint {
+            Endpoint::Inet(ref host) => {
+                UdpSocket::bind(&resolve(host, port)[..]).map(DatagramListener::Udp)
+            }
+            #[cfg(unix)]
+            Endpoint::UnixDgram(ref path) => {
+                let _ = fs::remove_file(path);
+                UnixDatagram::bind(path).map(DatagramListener::Unix)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a datagram endpoint",
+            )),
+        }
+    }
+
+    /// Receive the next datagram into `buf`, returning its length
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            DatagramListener::Udp(ref s) => s.recv(buf),
+            #[cfg(unix)]
+            DatagramListener::Unix(ref s) => s.recv(buf),
+        }
+    }
+}
+
+/// A datagram socket aimed at a single destination
+pub enum Datagram {
+    Udp { socket: UdpSocket, dest: SocketAddr },
+    #[cfg(unix)]
+    Unix(UnixDatagram),
+}
+
+impl Datagram {
+    /// Bind a UDP socket of `dest`'s address family on `port`, 0 for any
+    pub fn udp(dest: SocketAddr, port: u16) -> io::Result<Datagram> {
+        let addr: SocketAddr = match dest {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], port).into(),
+            SocketAddr::V6(_) => ([0u16; 8], port).into(),
+        };
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(Datagram::Udp { socket, dest })
+    }
+
+    /// Connect an unbound Unix datagram socket to `path`
+    #[cfg(unix)]
+    pub fn unix(path: &PathBuf) -> io::Result<Datagram> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(Datagram::Unix(socket))
+    }
+
+    #[cfg(not(unix))]
+    pub fn unix(_path: &PathBuf) -> io::Result<Datagram> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "unix sockets are not supported on this platform",
+        ))
+    }
+
+    /// Send one datagram
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Datagram::Udp {
+                ref socket,
+                ref dest,
+            } => socket.send_to(buf, dest),
+            #[cfg(unix)]
+            Datagram::Unix(ref socket) => socket.send(buf),
+        }
+    }
+}